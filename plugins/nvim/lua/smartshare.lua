@@ -1,5 +1,6 @@
 local M = {}
 local ns = vim.api.nvim_create_namespace('smartshare')
+local blame_ns = vim.api.nvim_create_namespace('smartshare_blame')
 local is_user_input = true
 
 local buf = nil
@@ -7,6 +8,7 @@ local handle = nil
 local initialized = false
 local ack_waiting = 0
 local is_attached = false
+local participants = {}
 
 function line_col_to_byte_offset(line, col)
     local line_start_offset = vim.api.nvim_buf_get_offset(buf, line)
@@ -37,7 +39,8 @@ function connect(addr)
         vim.fn.jobstop(handle)
     end
 
-    handle = vim.fn.jobstart("./client --format bytes " .. addr .. ":4903", {
+    local journal = vim.fn.stdpath("state") .. "/smartshare-journal.json"
+    handle = vim.fn.jobstart({ "./client", "--format", "bytes", "--journal", journal, addr }, {
         on_stdout = function(_job_id, data, event)
             for _, json_object in ipairs(data) do
                 if json_object ~= nil and json_object ~= '' then
@@ -69,6 +72,19 @@ function connect(addr)
                         initialized = true
                     end
 
+                    if message.action == "journal_found" then
+                        local choice = vim.fn.confirm(
+                            "Unsent edits from revision " .. message.rev_num .. " were found, replay them?",
+                            "&Replay\n&Drop"
+                        )
+                        send_message({ action = "replay_journal", replay = choice == 1 })
+                    end
+
+                    if message.action == "invite" then
+                        vim.fn.setreg('"', message.invite)
+                        vim.notify("Session invite (copied): " .. message.invite)
+                    end
+
                     if message.action == "error" then
                         vim.notify(message.error, "error")
                     end
@@ -77,6 +93,106 @@ function connect(addr)
                         ack_waiting = ack_waiting - 1
                     end
 
+                    if message.action == "roster" then
+                        participants = {}
+                        for _, participant in ipairs(message.participants) do
+                            set_participant(participant)
+                        end
+                    end
+
+                    if message.action == "joined" then
+                        set_participant(message)
+                        vim.notify(message.name .. " joined the session")
+                    end
+
+                    if message.action == "left" then
+                        local participant = participants[message.id]
+                        if participant ~= nil then
+                            vim.notify(participant.name .. " left the session")
+                        end
+                        participants[message.id] = nil
+                    end
+
+                    if message.action == "clear_cursors" then
+                        vim.api.nvim_buf_del_extmark(buf, ns, message.id + 1)
+                    end
+
+                    if message.action == "scroll_to" then
+                        local row, col = M.get_line_column_from_byte_offset(message.offset)
+                        vim.api.nvim_win_set_cursor(0, { row + 1, col })
+                        vim.cmd("normal! zz")
+                    end
+
+                    if message.action == "reveal" then
+                        vim.fn.winrestview({ topline = message.viewport.start + 1 })
+                    end
+
+                    if message.action == "chat" then
+                        vim.notify("[" .. (message.name or message.author) .. "] " .. message.text)
+                    end
+
+                    if message.action == "thread_updated" then
+                        local comment = message.comments[#message.comments]
+                        local row, _ = M.get_line_column_from_byte_offset(message.start)
+                        vim.notify("Thread " .. message.id .. " line " .. (row + 1) .. " ["
+                            .. (comment.name or comment.author) .. "] " .. comment.text)
+                    end
+
+                    if message.action == "activity" and participants[message.id] ~= nil then
+                        participants[message.id].activity = message.activity
+                    end
+
+                    if message.action == "presenter_changed" then
+                        if message.id == vim.NIL or message.id == nil then
+                            vim.notify("Presentation ended")
+                        else
+                            local participant = participants[message.id]
+                            vim.notify((participant and participant.name or message.id) .. " is presenting")
+                        end
+                    end
+
+                    if message.action == "blame" then
+                        vim.api.nvim_buf_clear_namespace(buf, blame_ns, 0, -1)
+                        for _, range in ipairs(message.ranges) do
+                            local participant = participants[range.author]
+                            local name = participant and participant.name or range.author
+                            vim.api.nvim_buf_set_extmark(buf, blame_ns, range.start, 0, {
+                                virt_text = { { name .. " @" .. range.rev_num, "Comment" } },
+                                virt_text_pos = "right_align",
+                            })
+                        end
+                    end
+
+                    if message.action == "diff" then
+                        local diff = vim.api.nvim_create_buf(false, true)
+                        vim.api.nvim_buf_set_lines(diff, 0, -1, false, vim.split(message.unified, "\n"))
+                        vim.bo[diff].modifiable = false
+                        vim.bo[diff].filetype = "diff"
+                        vim.cmd("vsplit")
+                        vim.api.nvim_win_set_buf(0, diff)
+                    end
+
+                    if message.action == "checkpoints" then
+                        local checkpoint = message.checkpoints[#message.checkpoints]
+                        vim.notify("Checkpoint " .. checkpoint.name .. " at revision " .. checkpoint.rev_num)
+                    end
+
+                    if message.action == "revision" then
+                        local history = vim.api.nvim_create_buf(false, true)
+                        vim.api.nvim_buf_set_lines(history, 0, -1, false, vim.split(message.file, "\n"))
+                        vim.bo[history].modifiable = false
+                        vim.bo[history].filetype = vim.bo[buf].filetype
+                        vim.api.nvim_buf_set_name(history, "smartshare://revision/" .. message.rev_num)
+                        vim.cmd("vsplit")
+                        vim.api.nvim_win_set_buf(0, history)
+                    end
+
+                    if message.action == "follow_ended" then
+                        local participant = participants[message.id]
+                        local name = participant and participant.name or message.id
+                        vim.notify("Stopped following " .. name)
+                    end
+
                     if message.action == "cursor" then
                         for _, cursor in ipairs(message.cursors) do
                             set_cursor(message.id, cursor.cursor, cursor.anchor)
@@ -167,7 +283,7 @@ end
 vim.api.nvim_create_user_command("SmartShareConnect", function(cmd)
     local addr = cmd.fargs[1]
     if addr == nil then
-        vim.notify("Please provide an IP address", "error")
+        vim.notify("Please provide an address or an invite", "error")
         return
     end
 
@@ -179,6 +295,116 @@ vim.api.nvim_create_user_command("SmartShareConnect", function(cmd)
     end
 end, {})
 
+vim.api.nvim_create_user_command("SmartShareFollow", function(cmd)
+    local id = tonumber(cmd.fargs[1])
+    if id == nil then
+        for participant_id, participant in pairs(participants) do
+            if participant.name == cmd.fargs[1] then
+                id = participant_id
+            end
+        end
+    end
+    send_message({
+        action = "follow",
+        id = id,
+    })
+end, { nargs = "?" })
+
+vim.api.nvim_create_user_command("SmartShareChat", function(cmd)
+    local message = {
+        action = "chat",
+        text = cmd.args,
+    }
+    if cmd.range > 0 then
+        message.anchor = vim.api.nvim_buf_get_offset(buf, cmd.line1 - 1)
+    end
+    send_message(message)
+end, { nargs = "+", range = true })
+
+vim.api.nvim_create_user_command("SmartShareComment", function(cmd)
+    local line_count = vim.api.nvim_buf_line_count(buf)
+    local range_end = vim.api.nvim_buf_get_offset(buf, math.min(cmd.line2, line_count))
+    if cmd.line2 >= line_count then
+        range_end = range_end - 1
+    end
+    send_message({
+        action = "create_thread",
+        start = vim.api.nvim_buf_get_offset(buf, cmd.line1 - 1),
+        ["end"] = math.max(0, range_end),
+        comment = { text = cmd.args },
+    })
+end, { nargs = "+", range = true })
+
+vim.api.nvim_create_user_command("SmartSharePresent", function(cmd)
+    send_message({
+        action = "present",
+        enabled = cmd.args ~= "off",
+    })
+end, { nargs = "?" })
+
+vim.api.nvim_create_user_command("SmartShareWatch", function(cmd)
+    send_message({
+        action = "watch_presenter",
+        enabled = cmd.args ~= "off",
+    })
+end, { nargs = "?" })
+
+vim.api.nvim_create_user_command("SmartShareUndo", function()
+    send_message({ action = "undo" })
+end, {})
+
+vim.api.nvim_create_user_command("SmartShareRedo", function()
+    send_message({ action = "redo" })
+end, {})
+
+vim.api.nvim_create_user_command("SmartShareRevision", function(cmd)
+    send_message({
+        action = "request_revision",
+        rev_num = tonumber(cmd.args),
+    })
+end, { nargs = 1 })
+
+vim.api.nvim_create_user_command("SmartShareBlame", function(cmd)
+    if cmd.args == "off" then
+        vim.api.nvim_buf_clear_namespace(buf, blame_ns, 0, -1)
+    else
+        send_message({ action = "request_blame" })
+    end
+end, { nargs = "?" })
+
+vim.api.nvim_create_user_command("SmartShareDiff", function(cmd)
+    send_message({
+        action = "request_diff",
+        from = tonumber(cmd.fargs[1]),
+        to = tonumber(cmd.fargs[2]),
+    })
+end, { nargs = "+" })
+
+vim.api.nvim_create_user_command("SmartShareCheckpoint", function(cmd)
+    send_message({ action = "checkpoint", name = cmd.args })
+end, { nargs = 1 })
+
+vim.api.nvim_create_user_command("SmartShareRevert", function(cmd)
+    local rev_num = tonumber(cmd.args)
+    if rev_num ~= nil then
+        send_message({ action = "revert", rev_num = rev_num })
+    else
+        send_message({ action = "revert_to_checkpoint", name = cmd.args })
+    end
+end, { nargs = 1 })
+
+vim.api.nvim_create_user_command("SmartShareFork", function()
+    send_message({ action = "fork" })
+end, {})
+
+vim.api.nvim_create_user_command("SmartShareMerge", function(cmd)
+    if cmd.args == "discard" then
+        send_message({ action = "discard_fork" })
+    else
+        send_message({ action = "merge" })
+    end
+end, { nargs = "?" })
+
 function reverse_rgb(r, g, b)
     -- Calculate the complementary color by subtracting each component from 255
     local reversed_r = 255 - r
@@ -188,6 +414,19 @@ function reverse_rgb(r, g, b)
     return reversed_r, reversed_g, reversed_b
 end
 
+function set_participant(participant)
+    participants[participant.id] = participant
+    local r = tonumber(participant.color:sub(2, 3), 16)
+    local g = tonumber(participant.color:sub(4, 5), 16)
+    local b = tonumber(participant.color:sub(6, 7), 16)
+    if r == nil or g == nil or b == nil then
+        return
+    end
+    local reversed_r, reversed_g, reversed_b = reverse_rgb(r, g, b)
+    local reversed_rgb = string.format("#%02X%02X%02X", reversed_r, reversed_g, reversed_b)
+    vim.api.nvim_set_hl(0, "SmartShareCursor" .. participant.id, { bg = participant.color, fg = reversed_rgb })
+end
+
 function set_cursor(id, offset, anchor)
     local start_row, start_col = M.get_line_column_from_byte_offset(anchor)
     local end_row, end_col = M.get_line_column_from_byte_offset(offset)
@@ -222,6 +461,11 @@ function set_cursor(id, offset, anchor)
             id,
         strict = false,
     }
+    local participant = participants[id]
+    if participant ~= nil then
+        extmark_opts.virt_text = { { participant.name, "SmartShareCursor" .. id } }
+        extmark_opts.virt_text_pos = "eol"
+    end
     vim.api.nvim_buf_set_extmark(buf, ns, start_row, start_col, extmark_opts)
 end
 
@@ -266,4 +510,37 @@ vim.api.nvim_create_autocmd({ "CursorMoved", "CursorMovedI" }, {
     end
 })
 
+vim.api.nvim_create_autocmd({ "WinScrolled" }, {
+    pattern = { "*" },
+    callback = function()
+        if initialized and vim.api.nvim_get_current_buf() == buf then
+            send_message({
+                action = "viewport",
+                viewports = {
+                    {
+                        start = vim.fn.line("w0") - 1,
+                        ["end"] = vim.fn.line("w$"),
+                    }
+                }
+            })
+        end
+    end
+})
+
+vim.api.nvim_create_autocmd({ "FocusLost" }, {
+    callback = function()
+        if initialized then
+            send_message({ action = "detach" })
+        end
+    end
+})
+
+vim.api.nvim_create_autocmd({ "FocusGained" }, {
+    callback = function()
+        if initialized then
+            send_message({ action = "attach" })
+        end
+    end
+})
+
 return M
@@ -1,7 +1,7 @@
 import * as vscode from 'vscode';
 import { logClient, logServer } from './utils';
 import { ChildProcessWithoutNullStreams, spawn } from 'child_process';
-import { Ack, Cursors, Cursor, File, Message, RequestFile, TextModification, Update, isMessage, matchMessage } from './message';
+import { Ack, Activity, ClearCursors, Cursors, Cursor, File, Invite, Joined, Left, Message, Participant, RequestFile, Roster, TextModification, Update, Welcome, isMessage, matchMessage } from './message';
 
 let waitingAcks = 0;
 let toIgnore: string[] = [];
@@ -13,10 +13,12 @@ let changeSelectionsDisposable: vscode.Disposable;
 let statusBarItem: vscode.StatusBarItem;
 let init = true;
 let cursorsDecorations: Map<number, vscode.Disposable[]> = new Map();
+let participants: Map<number, Participant> = new Map();
+let clientId: number | undefined;
+let activities: Map<number, string> = new Map();
 
 const EXE_PATH = __dirname + '/../../../../smartshare/target/debug/';
 const DEFAULT_ADDR = "127.0.0.1";
-const DEFAULT_PORT = "4903";
 const CURSOR_COLORS = ["Salmon", "YellowGreen", "SteelBlue", "MediumOrchid", "DarkOrange", "Aqua"];
 
 function procWrite(proc: ChildProcessWithoutNullStreams, message: Message): void {
@@ -31,7 +33,7 @@ function updateCursors(cursors: Cursors) {
         let decoration = vscode.window.createTextEditorDecorationType({
             borderWidth: "0 2px 0 0",
             borderStyle: "solid",
-            borderColor: CURSOR_COLORS[cursors.id % CURSOR_COLORS.length],
+            borderColor: participants.get(cursors.id)?.color ?? CURSOR_COLORS[cursors.id % CURSOR_COLORS.length],
             backgroundColor: new vscode.ThemeColor("editor.selectionBackground"),
         });
         decorations.push(decoration);
@@ -43,6 +45,19 @@ function updateCursors(cursors: Cursors) {
     cursorsDecorations.set(cursors.id, decorations);
 }
 
+function updateTooltip() {
+    statusBarItem.tooltip = Array.from(participants.values())
+        .map((participant) => participant.name + ": " + (activities.get(participant.id) ?? "active"))
+        .join("\n");
+}
+
+function clearCursors(id: number) {
+    cursorsDecorations.get(id)?.forEach((decoration) => {
+        decoration.dispose();
+    });
+    cursorsDecorations.delete(id);
+}
+
 async function applyChange(change: TextModification): Promise<boolean> {
     toIgnore.push(JSON.stringify(change));
     let res = await change.write(editor);
@@ -62,11 +77,11 @@ function clientStdoutHandler(): (chunk: any) => void {
             if (data_line.length > 0) {
                 logClient.debug("recieved data", data_line);
                 const data = JSON.parse(data_line);
-                if (!isMessage(data)) {
-                    logClient.error("invalid action " + data.action)
-                    break;
+                if (isMessage(data)) {
+                    handleMessage(data);
+                } else {
+                    logClient.debug("Ignore unknown action " + data.action);
                 }
-                handleMessage(data);
             }
             data_line = '';
         }
@@ -128,6 +143,42 @@ function handleMessage(data: Message) {
         },
         (cursors: Cursors) => {
             updateCursors(cursors);
+        },
+        (clear: ClearCursors) => {
+            clearCursors(clear.id);
+        },
+        (welcome: Welcome) => {
+            clientId = welcome.id;
+        },
+        (invite: Invite) => {
+            vscode.window.showInformationMessage("Session invite: " + invite.invite, "Copy").then((choice) => {
+                if (choice == "Copy") {
+                    vscode.env.clipboard.writeText(invite.invite);
+                }
+            });
+        },
+        (joined: Joined) => {
+            participants.set(joined.id, { id: joined.id, name: joined.name, color: joined.color });
+            vscode.window.showInformationMessage(joined.name + " joined the session");
+        },
+        (left: Left) => {
+            const participant = participants.get(left.id);
+            if (participant) {
+                vscode.window.showInformationMessage(participant.name + " left the session");
+            }
+            participants.delete(left.id);
+            activities.delete(left.id);
+            updateTooltip();
+        },
+        (roster: Roster) => {
+            participants = new Map(roster.participants.map((participant): [number, Participant] => [participant.id, participant]));
+            const name = clientId !== undefined ? participants.get(clientId)?.name : undefined;
+            statusBarItem.text = (name ? "Connected as " + name : "Connected") + " (" + participants.size + " participants)";
+            updateTooltip();
+        },
+        (activity: Activity) => {
+            activities.set(activity.id, activity.activity);
+            updateTooltip();
         }
     );
 }
@@ -209,7 +260,7 @@ async function startClient(addr: string) {
 
     let client = spawn(
         EXE_PATH + "client",
-        [addr + ":" + DEFAULT_PORT, "--format", "chars"],
+        [addr, "--format", "chars"],
         { env: { RUST_LOG: 'trace' } }
     );
     clientProc = client;
@@ -246,6 +297,8 @@ async function startClient(addr: string) {
         }
         statusBarItem.text = "Disconnected";
         clientProc = undefined;
+        clientId = undefined;
+        activities.clear();
     });
 
     changeDocumentDisposable = vscode.workspace.onDidChangeTextDocument(changeDocumentHandler);
@@ -308,7 +361,7 @@ export async function activate(context: vscode.ExtensionContext) {
             return;
         }
         const addr = await vscode.window.showInputBox(
-            { prompt: "IP Address", value: DEFAULT_ADDR, placeHolder: DEFAULT_ADDR }
+            { prompt: "Address or invite", value: DEFAULT_ADDR, placeHolder: DEFAULT_ADDR }
         );
         if (!addr) {
             return;
@@ -1,7 +1,7 @@
 import * as vscode from 'vscode';
 import { logClient } from './utils';
 
-export type Message = Update | Error | RequestFile | File | Ack | Cursors;
+export type Message = Update | Error | RequestFile | File | Ack | Cursors | ClearCursors | Welcome | Invite | Joined | Left | Roster | Activity;
 
 export interface Update {
     action: "update"
@@ -58,13 +58,54 @@ export interface Cursors {
     cursors: Cursor[]
 }
 
+export interface ClearCursors {
+    action: "clear_cursors"
+    id: number
+}
+
 export interface Cursor {
     cursor: number
     anchor: number
 }
 
+export interface Participant {
+    id: number
+    name: string
+    color: string
+}
+
+export interface Welcome {
+    action: "welcome"
+    id: number
+}
+
+export interface Invite {
+    action: "invite"
+    invite: string
+}
+
+export interface Joined extends Participant {
+    action: "joined"
+}
+
+export interface Left {
+    action: "left"
+    id: number
+}
+
+export interface Roster {
+    action: "roster"
+    participants: Participant[]
+}
+
+export interface Activity {
+    action: "activity"
+    id: number
+    activity: "typing" | "active" | "idle" | "away"
+}
+
 export function isMessage(object: any): object is Message {
-    return ["update", "error", "request_file", "file", "ack", "cursor"].includes(object.action);
+    return ["update", "error", "request_file", "file", "ack", "cursor", "clear_cursors", "welcome", "invite", "joined", "left", "roster", "activity"].includes(object.action);
 }
 
 export function matchMessage(message: Message): any {
@@ -75,6 +116,13 @@ export function matchMessage(message: Message): any {
         onFile: (x: File) => any,
         onAck: (x: Ack) => any,
         onCursor: (x: Cursors) => any,
+        onClearCursors: (x: ClearCursors) => any,
+        onWelcome: (x: Welcome) => any,
+        onInvite: (x: Invite) => any,
+        onJoined: (x: Joined) => any,
+        onLeft: (x: Left) => any,
+        onRoster: (x: Roster) => any,
+        onActivity: (x: Activity) => any,
     ) => {
         switch (message.action) {
             case "update":
@@ -89,6 +137,20 @@ export function matchMessage(message: Message): any {
                 return onAck(message);
             case "cursor":
                 return onCursor(message);
+            case "clear_cursors":
+                return onClearCursors(message);
+            case "welcome":
+                return onWelcome(message);
+            case "invite":
+                return onInvite(message);
+            case "joined":
+                return onJoined(message);
+            case "left":
+                return onLeft(message);
+            case "roster":
+                return onRoster(message);
+            case "activity":
+                return onActivity(message);
         }
     }
 }
\ No newline at end of file
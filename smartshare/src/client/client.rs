@@ -1,22 +1,43 @@
-use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Result};
 use operational_transform::OperationSeq;
 use smartshare::{
+    crypto::SessionKey,
     file::File,
     protocol::msg::{
-        modif_to_operation_seq, to_ide_changes, CursorsInfo, Format, MessageIde, MessageServer,
-        ModifRequest, TextModification,
+        modif_to_operation_seq, to_ide_changes, transform_cursors, transform_index,
+        transform_viewports, Activity, ActivityInfo, ChatMessage, Comment, Cursor, CursorsInfo,
+        Format, MessageIde, MessageServer, ModifRequest, NewThread, Resume, TextModification,
+        Thread, Viewport, ViewportInfo,
     },
 };
 
 use crate::ide::Ide;
+use crate::journal::Journal;
 use crate::server::Server;
 use tracing::warn;
 
+/// How long after the last modification a participant is still typing.
+const TYPING_DELAY: Duration = Duration::from_secs(2);
+const UNDO_LIMIT: usize = 200;
+
+/// Private continuation of the shared document, kept apart until it is merged back.
+struct Branch {
+    /// Merged document at the time of the fork.
+    base: String,
+    /// Own changes on the branch since the fork.
+    delta: OperationSeq,
+    /// Changes of the shared document since the fork.
+    main: OperationSeq,
+}
+
 pub struct Client {
     server_state: OperationSeq,
     server_sent_delta: OperationSeq,
     server_unsent_delta: OperationSeq,
-    ide_state: OperationSeq,
     ide_sent_delta: OperationSeq,
     ide_unsent_delta: OperationSeq,
     rev_num: usize,
@@ -25,6 +46,31 @@ pub struct Client {
     client_id: usize,
     format: Format,
     file: Option<File>,
+    key: Option<SessionKey>,
+    remote_cursors: HashMap<usize, Vec<Cursor>>,
+    remote_viewports: HashMap<usize, Vec<Viewport>>,
+    following: Option<usize>,
+    scrolled_to: Option<u64>,
+    revealed: Option<Viewport>,
+    last_input: Instant,
+    last_change: Option<Instant>,
+    detached: bool,
+    idle_after: Duration,
+    activity: Option<Activity>,
+    presenter: Option<usize>,
+    watch_presenter: bool,
+    undo_stack: Vec<OperationSeq>,
+    redo_stack: Vec<OperationSeq>,
+    last_recorded: Option<Instant>,
+    journal: Option<PathBuf>,
+    journaled: bool,
+    /// Set from losing the connection until the server sent everything that was missed.
+    resuming: bool,
+    /// Nonce of the server session, which only lets its own clients resume.
+    session: Option<String>,
+    /// Threads waiting for the edits their range takes into account to be sent.
+    pending_threads: Vec<NewThread>,
+    branch: Option<Branch>,
 }
 
 impl Client {
@@ -33,7 +79,6 @@ impl Client {
             server_state: OperationSeq::default(),
             server_sent_delta: OperationSeq::default(),
             server_unsent_delta: OperationSeq::default(),
-            ide_state: OperationSeq::default(),
             ide_sent_delta: OperationSeq::default(),
             ide_unsent_delta: OperationSeq::default(),
             rev_num: 0,
@@ -42,6 +87,109 @@ impl Client {
             client_id,
             format,
             file: None,
+            key: None,
+            remote_cursors: HashMap::new(),
+            remote_viewports: HashMap::new(),
+            following: None,
+            scrolled_to: None,
+            revealed: None,
+            last_input: Instant::now(),
+            last_change: None,
+            detached: false,
+            idle_after: Duration::from_secs(60),
+            activity: None,
+            presenter: None,
+            watch_presenter: true,
+            undo_stack: vec![],
+            redo_stack: vec![],
+            last_recorded: None,
+            journal: None,
+            journaled: false,
+            resuming: false,
+            session: None,
+            pending_threads: vec![],
+            branch: None,
+        }
+    }
+
+    pub fn with_session_key(mut self, key: SessionKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_idle_after(mut self, idle_after: Duration) -> Self {
+        self.idle_after = idle_after;
+        self
+    }
+
+    pub fn with_journal(mut self, journal: PathBuf) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Picks the state of a previous run back up, to be resumed with the server.
+    pub async fn restore(&mut self, journal: Journal) -> Result<()> {
+        ensure!(
+            journal.unsent.target_len() == journal.text.chars().count(),
+            "The journal does not match its text"
+        );
+        let file = File::new(&journal.text);
+        self.rev_num = journal.rev_num;
+        self.client_id = journal.id;
+        self.session = journal.session;
+        self.server_state = OperationSeq::default();
+        self.server_state.retain(journal.sent.base_len() as u64);
+        self.server_sent_delta = journal.sent;
+        self.server_unsent_delta = journal.unsent;
+        self.ide_sent_delta = OperationSeq::default();
+        self.ide_sent_delta.retain(file.len_chars() as u64);
+        self.ide_unsent_delta = self.ide_sent_delta.clone();
+        self.file = Some(file);
+        self.journaled = true;
+        self.resuming = true;
+        self.ide.send(MessageIde::File { file: journal.text }).await;
+        Ok(())
+    }
+
+    async fn write_journal(&mut self) -> Result<()> {
+        let Some(path) = &self.journal else {
+            return Ok(());
+        };
+        if self.server_sent_delta.is_noop() && self.server_unsent_delta.is_noop() {
+            if self.journaled {
+                Journal::clear(path).await?;
+                self.journaled = false;
+            }
+            return Ok(());
+        }
+
+        let journal = Journal {
+            rev_num: self.rev_num,
+            id: self.client_id,
+            session: self.session.clone(),
+            text: self.merged_text()?,
+            sent: self.server_sent_delta.clone(),
+            unsent: self.server_unsent_delta.clone(),
+        };
+        journal.save(path).await?;
+        self.journaled = true;
+        Ok(())
+    }
+
+    fn open_sealed<T>(
+        &self,
+        sealed: Option<&str>,
+        open: impl FnOnce(&SessionKey, &str) -> Result<T>,
+    ) -> Result<Option<T>> {
+        match (&self.key, sealed) {
+            (Some(key), Some(sealed)) => open(key, sealed).map(Some),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(anyhow!(
+                "Received unencrypted data in an end-to-end session"
+            )),
+            (None, Some(_)) => Err(anyhow!(
+                "Received encrypted data, a session secret is required"
+            )),
         }
     }
 
@@ -58,26 +206,97 @@ impl Client {
         Ok(())
     }
 
+    /// Where to pick the session up after losing the connection, once the file is known.
+    pub fn resume(&self) -> Option<Resume> {
+        self.file.as_ref()?;
+        Some(Resume {
+            rev_num: self.rev_num,
+            id: self.client_id,
+            session: self.session.clone()?,
+        })
+    }
+
+    pub async fn on_disconnect(&mut self) {
+        self.server = Server::offline();
+        self.resuming = true;
+        self.ide
+            .send(MessageIde::Error {
+                error: "Connection to the server lost, edits are kept until it is back".into(),
+            })
+            .await;
+    }
+
+    /// Continues the session through a new connection, whose server sends the missed revisions.
+    pub async fn on_reconnect(&mut self, server: Server) {
+        self.server = server;
+        // The participants are announced again by the server
+        let ids: HashSet<usize> = self
+            .remote_cursors
+            .keys()
+            .chain(self.remote_viewports.keys())
+            .copied()
+            .collect();
+        for id in ids {
+            self.ide.send(MessageIde::ClearCursors { id }).await;
+        }
+        self.remote_cursors.clear();
+        self.remote_viewports.clear();
+    }
+
+    async fn on_resumed(&mut self) -> Result<()> {
+        self.resuming = false;
+        // Not acknowledged while catching up, so what was in flight never reached the server
+        if !self.server_sent_delta.is_noop() {
+            self.server_unsent_delta = self.server_sent_delta.compose(&self.server_unsent_delta)?;
+            self.server_sent_delta = OperationSeq::default();
+            self.server_sent_delta
+                .retain(self.server_state.target_len() as u64);
+        }
+        if !self.server_unsent_delta.is_noop() {
+            self.submit_server_change().await;
+        }
+        Ok(())
+    }
+
     async fn submit_server_change(&mut self) {
+        // Only what was in flight when the connection was lost may be acknowledged while resuming
+        if self.resuming {
+            return;
+        }
+        let (delta, sealed) = match &self.key {
+            Some(key) => {
+                let (delta, sealed) = key.seal_delta(&self.server_unsent_delta);
+                (delta, Some(sealed))
+            }
+            None => (self.server_unsent_delta.clone(), None),
+        };
         let _ = self
             .server
             .send(MessageServer::ServerUpdate(ModifRequest {
-                delta: self.server_unsent_delta.clone(),
+                delta,
                 rev_num: self.rev_num,
+                sealed,
             }))
             .await;
         self.server_sent_delta = self.server_unsent_delta.clone();
         self.server_unsent_delta = OperationSeq::default();
         self.server_unsent_delta
             .retain(self.server_sent_delta.target_len() as u64);
+        for new_thread in std::mem::take(&mut self.pending_threads) {
+            let _ = self.send_thread(new_thread).await;
+        }
     }
 
     async fn on_server_change(&mut self, modif: &ModifRequest) -> Result<()> {
+        let server_change = &self
+            .open_sealed(modif.sealed.as_deref(), |key, sealed| {
+                key.open_delta(&modif.delta, sealed)
+            })?
+            .unwrap_or_else(|| modif.delta.clone());
         self.rev_num += 1;
         if self.rev_num != modif.rev_num {
             todo!("handle desynchronisation");
         }
-        let server_change = &modif.delta;
 
         let new_server_state = self.server_state.compose(server_change).unwrap();
         let (updated_server_change, new_server_sent_delta) =
@@ -91,6 +310,25 @@ impl Client {
         self.server_sent_delta = new_server_sent_delta;
         self.server_unsent_delta = new_server_unsent_delta;
 
+        for cursors in self.remote_cursors.values_mut() {
+            transform_cursors(&ide_delta, cursors);
+        }
+        for viewports in self.remote_viewports.values_mut() {
+            transform_viewports(&ide_delta, viewports);
+        }
+        for new_thread in self.pending_threads.iter_mut() {
+            new_thread.start = transform_index(&ide_delta, new_thread.start);
+            new_thread.end = transform_index(&ide_delta, new_thread.end);
+        }
+
+        // The ide shows the branch, which only gets the shared changes when merged
+        if let Some(branch) = &mut self.branch {
+            branch.main = branch.main.compose(&ide_delta)?;
+            return Ok(());
+        }
+        for entry in self.undo_stack.iter_mut().chain(self.redo_stack.iter_mut()) {
+            *entry = entry.transform(&ide_delta)?.0;
+        }
         self.ide_unsent_delta = self.ide_unsent_delta.compose(&ide_delta).unwrap();
         if self.ide_sent_delta.is_noop() && !self.ide_unsent_delta.is_noop() {
             self.submit_ide_change().await?;
@@ -132,34 +370,70 @@ impl Client {
         Ok(())
     }
 
-    async fn on_receive_file(&mut self, file_str: String, version: usize) -> Result<()> {
+    async fn on_receive_file(
+        &mut self,
+        file_str: String,
+        version: usize,
+        sealed: Option<String>,
+    ) -> Result<()> {
+        let file_str = self
+            .open_sealed(sealed.as_deref(), |key, sealed| {
+                key.open_text(&file_str, sealed)
+            })?
+            .unwrap_or(file_str);
         let file = File::new(&file_str);
-        self.server_state.retain(file.len_chars() as u64);
-        self.server_sent_delta.retain(file.len_chars() as u64);
-        self.server_unsent_delta.retain(file.len_chars() as u64);
-        self.ide_sent_delta.retain(file.len_chars() as u64);
-        self.ide_unsent_delta.retain(file.len_chars() as u64);
+        // Sent instead of the missed revisions when the server could not resume the session
+        let discarded = self.resuming
+            && !(self.server_sent_delta.is_noop() && self.server_unsent_delta.is_noop());
+        self.reset(file.len_chars(), version);
         self.ide.send(MessageIde::File { file: file_str }).await;
-        self.rev_num = version;
         self.file = Some(file);
+        if discarded {
+            self.ide
+                .send(MessageIde::Error {
+                    error: "Could not resume the session, the edits made meanwhile were discarded"
+                        .into(),
+                })
+                .await;
+        }
 
         Ok(())
     }
 
+    /// Starts over from a document of `len` characters, as revision `rev_num` of the server.
+    fn reset(&mut self, len: usize, rev_num: usize) {
+        let mut retain = OperationSeq::default();
+        retain.retain(len as u64);
+        self.server_state = retain.clone();
+        self.server_sent_delta = retain.clone();
+        self.server_unsent_delta = retain.clone();
+        self.ide_sent_delta = retain.clone();
+        self.ide_unsent_delta = retain;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.pending_threads.clear();
+        self.rev_num = rev_num;
+        self.resuming = false;
+    }
+
     async fn on_ide_file(&mut self, file_str: String) -> Result<()> {
-        self.rev_num = 0;
         let file = File::new(&file_str);
-        self.server_state.retain(file.len_chars() as u64);
-        self.server_sent_delta.retain(file.len_chars() as u64);
-        self.server_unsent_delta.retain(file.len_chars() as u64);
-        self.ide_sent_delta.retain(file.len_chars() as u64);
-        self.ide_unsent_delta.retain(file.len_chars() as u64);
+        // Anything restored from a journal is part of the document the ide sends
+        self.reset(file.len_chars(), 0);
         self.file = Some(file);
+        let (file_str, sealed) = match &self.key {
+            Some(key) => {
+                let (file_str, sealed) = key.seal_text(&file_str);
+                (file_str, Some(sealed))
+            }
+            None => (file_str, None),
+        };
         let _ = self
             .server
             .send(MessageServer::File {
                 file: file_str,
                 version: 0,
+                sealed,
             })
             .await;
 
@@ -167,6 +441,8 @@ impl Client {
     }
 
     async fn on_ide_change(&mut self, mut changes: Vec<TextModification>) -> Result<()> {
+        self.end_follow().await;
+        let merged = self.merged_text()?;
         let file = self.file.as_mut().ok_or_else(|| anyhow!("File not set"))?;
 
         let ide_seq = {
@@ -191,10 +467,8 @@ impl Client {
             .transform(&self.ide_unsent_delta)
             .unwrap();
 
-        self.server_unsent_delta = self
-            .server_unsent_delta
-            .compose(&server_delta)
-            .expect("modifs_to_operation_seq result should be length compatible with op_seq");
+        self.record_undo(&server_delta, &merged)?;
+        self.apply_local_change(&server_delta)?;
 
         self.ide_sent_delta = new_ide_sent_delta;
         self.ide_unsent_delta = new_ide_unsent_delta;
@@ -215,6 +489,154 @@ impl Client {
         Ok(())
     }
 
+    /// Text of the merged document, that is the ide's document with the pending changes applied.
+    fn merged_text(&self) -> Result<String> {
+        let mut file = self.file.clone().ok_or_else(|| anyhow!("File not set"))?;
+        file.apply(&self.ide_sent_delta)?;
+        file.apply(&self.ide_unsent_delta)?;
+        Ok(file.to_string())
+    }
+
+    fn record_undo(&mut self, delta: &OperationSeq, merged: &str) -> Result<()> {
+        // Changes typed in a row are undone together
+        let now = Instant::now();
+        let group = self
+            .last_recorded
+            .is_some_and(|last| now.duration_since(last) < TYPING_DELAY);
+        self.last_recorded = Some(now);
+
+        let mut inverse = delta.invert(merged);
+        if group {
+            if let Some(last) = self.undo_stack.pop() {
+                inverse = inverse.compose(&last)?;
+            }
+        }
+        for entry in self.undo_stack.iter_mut() {
+            *entry = entry.transform(delta)?.0;
+        }
+        self.undo_stack.push(inverse);
+        if self.undo_stack.len() > UNDO_LIMIT {
+            self.undo_stack.remove(0);
+        }
+        self.redo_stack.clear();
+        Ok(())
+    }
+
+    /// Reverts the latest own change still in the history, leaving the others' changes intact.
+    async fn on_undo(&mut self, redo: bool) -> Result<()> {
+        let merged = self.merged_text()?;
+        let (stack, other) = if redo {
+            (&mut self.redo_stack, &mut self.undo_stack)
+        } else {
+            (&mut self.undo_stack, &mut self.redo_stack)
+        };
+        // Changes entirely overwritten by the others have nothing left to revert
+        let delta = std::iter::from_fn(|| stack.pop())
+            .find(|delta| !delta.is_noop())
+            .ok_or_else(|| anyhow!("Nothing to {}", if redo { "redo" } else { "undo" }))?;
+        for entry in stack.iter_mut().chain(other.iter_mut()) {
+            *entry = entry.transform(&delta)?.0;
+        }
+        other.push(delta.invert(&merged));
+        self.last_recorded = None;
+        self.submit_local_change(delta).await
+    }
+
+    /// Applies a change of the merged document that did not come from the ide.
+    async fn submit_local_change(&mut self, delta: OperationSeq) -> Result<()> {
+        self.apply_local_change(&delta)?;
+        self.ide_unsent_delta = self.ide_unsent_delta.compose(&delta)?;
+
+        if self.ide_sent_delta.is_noop() {
+            self.submit_ide_change().await?;
+        }
+        if self.server_sent_delta.is_noop() && !self.server_unsent_delta.is_noop() {
+            self.submit_server_change().await;
+        }
+        Ok(())
+    }
+
+    /// Takes a change of the merged document into the branch if any, or else into the shared one.
+    fn apply_local_change(&mut self, delta: &OperationSeq) -> Result<()> {
+        if let Some(branch) = &mut self.branch {
+            branch.delta = branch.delta.compose(delta)?;
+            return Ok(());
+        }
+        self.server_unsent_delta = self.server_unsent_delta.compose(delta)?;
+        for cursors in self.remote_cursors.values_mut() {
+            transform_cursors(delta, cursors);
+        }
+        for viewports in self.remote_viewports.values_mut() {
+            transform_viewports(delta, viewports);
+        }
+        for new_thread in self.pending_threads.iter_mut() {
+            new_thread.start = transform_index(delta, new_thread.start);
+            new_thread.end = transform_index(delta, new_thread.end);
+        }
+        Ok(())
+    }
+
+    async fn on_fork(&mut self) -> Result<()> {
+        ensure!(self.branch.is_none(), "Already working on a private branch");
+        let base = self.merged_text()?;
+        let mut delta = OperationSeq::default();
+        delta.retain(base.chars().count() as u64);
+        self.end_follow().await;
+        self.branch = Some(Branch {
+            base,
+            main: delta.clone(),
+            delta,
+        });
+        Ok(())
+    }
+
+    /// Submits the branch to the shared document, past the changes made there since the fork.
+    async fn on_merge(&mut self) -> Result<()> {
+        let branch = self
+            .branch
+            .take()
+            .ok_or_else(|| anyhow!("Not working on a private branch"))?;
+        let (main, delta) = branch.main.transform(&branch.delta)?;
+        self.apply_local_change(&delta)?;
+        self.catch_up(&main).await?;
+        if self.server_sent_delta.is_noop() && !self.server_unsent_delta.is_noop() {
+            self.submit_server_change().await;
+        }
+        Ok(())
+    }
+
+    async fn on_discard_fork(&mut self) -> Result<()> {
+        let branch = self
+            .branch
+            .take()
+            .ok_or_else(|| anyhow!("Not working on a private branch"))?;
+        let revert = branch.delta.invert(&branch.base).compose(&branch.main)?;
+        self.catch_up(&revert).await
+    }
+
+    /// Brings the ide from the branch back to the shared document.
+    async fn catch_up(&mut self, delta: &OperationSeq) -> Result<()> {
+        for entry in self.undo_stack.iter_mut().chain(self.redo_stack.iter_mut()) {
+            *entry = entry.transform(delta)?.0;
+        }
+        self.ide_unsent_delta = self.ide_unsent_delta.compose(delta)?;
+        if self.ide_sent_delta.is_noop() && !self.ide_unsent_delta.is_noop() {
+            self.submit_ide_change().await?;
+        }
+        Ok(())
+    }
+
+    /// Deltas carrying positions from the shared document over to the branch.
+    fn to_branch(&self) -> Result<Vec<OperationSeq>> {
+        let Some(branch) = &self.branch else {
+            return Ok(vec![]);
+        };
+        // Both documents lead to the same one, from which the shared changes are reverted
+        let (main, delta) = branch.main.transform(&branch.delta)?;
+        let text = self.merged_text()?;
+        Ok(vec![delta, main.invert(&text)])
+    }
+
     async fn on_ide_ack(&mut self) -> Result<()> {
         if self.ide_sent_delta.is_noop() {
             bail!("ack not ok");
@@ -238,6 +660,14 @@ impl Client {
     }
 
     async fn on_ide_cursor_move(&mut self, mut cursor_info: CursorsInfo) -> Result<()> {
+        // Following moves the ide cursor, any other move means the user wants to leave
+        if self.following.is_some() && !self.is_hinted_move(&cursor_info.cursors) {
+            self.end_follow().await;
+        }
+        // Positions in the branch mean nothing to the others
+        if self.branch.is_some() {
+            return Ok(());
+        }
         let file = self.file.as_mut().ok_or_else(|| anyhow!("File not set"))?;
         if matches!(self.format, Format::Bytes) {
             let _ = file.byte_to_char_cursor(&mut cursor_info);
@@ -248,29 +678,485 @@ impl Client {
                 return Ok(());
             }
         }
+        // The server expects cursors in the merged document, that is the latest revision plus our
+        // pending modifications
+        transform_cursors(&self.ide_sent_delta, &mut cursor_info.cursors);
+        transform_cursors(&self.ide_unsent_delta, &mut cursor_info.cursors);
+        cursor_info.rev_num = Some(self.rev_num);
         let _ = self.server.send(MessageServer::Cursor(cursor_info)).await;
         Ok(())
     }
 
     async fn on_server_cursor_move(&mut self, mut cursor_info: CursorsInfo) -> Result<()> {
-        let file = self.file.as_mut().ok_or_else(|| anyhow!("File not set"))?;
+        if cursor_info.rev_num.take() == Some(self.rev_num) {
+            transform_cursors(&self.server_sent_delta, &mut cursor_info.cursors);
+            transform_cursors(&self.server_unsent_delta, &mut cursor_info.cursors);
+        }
+        let Some(id) = cursor_info.id else {
+            bail!("Received cursors without participant");
+        };
+        self.remote_cursors.insert(id, cursor_info.cursors.clone());
+
+        self.cursors_to_ide(&mut cursor_info.cursors)?;
+        if self.following == Some(id) || self.watches(id) {
+            self.scroll_to(id, &cursor_info.cursors).await;
+        }
+        self.ide.send(MessageIde::Cursor(cursor_info)).await;
+        Ok(())
+    }
+
+    async fn on_ide_viewport_move(&mut self, mut viewport_info: ViewportInfo) -> Result<()> {
+        if self.branch.is_some() {
+            return Ok(());
+        }
+        let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
+        for viewport in viewport_info.viewports.iter_mut() {
+            ensure!(
+                viewport.start <= viewport.end && viewport.end <= file.len_lines() as u64,
+                "Invalid viewport"
+            );
+            viewport.start = file.line_to_char(viewport.start as usize) as u64;
+            viewport.end = file.line_to_char(viewport.end as usize) as u64;
+        }
+        transform_viewports(&self.ide_sent_delta, &mut viewport_info.viewports);
+        transform_viewports(&self.ide_unsent_delta, &mut viewport_info.viewports);
+        viewport_info.rev_num = Some(self.rev_num);
+        let _ = self
+            .server
+            .send(MessageServer::Viewport(viewport_info))
+            .await;
+        Ok(())
+    }
+
+    async fn on_server_viewport_move(&mut self, mut viewport_info: ViewportInfo) -> Result<()> {
+        if viewport_info.rev_num.take() == Some(self.rev_num) {
+            transform_viewports(&self.server_sent_delta, &mut viewport_info.viewports);
+            transform_viewports(&self.server_unsent_delta, &mut viewport_info.viewports);
+        }
+        let Some(id) = viewport_info.id else {
+            bail!("Received viewports without participant");
+        };
+        self.remote_viewports
+            .insert(id, viewport_info.viewports.clone());
+
+        self.viewports_to_ide(&mut viewport_info.viewports)?;
+        if self.following == Some(id) || self.watches(id) {
+            self.reveal(id, &viewport_info.viewports).await;
+        }
+        self.ide.send(MessageIde::Viewport(viewport_info)).await;
+        Ok(())
+    }
+
+    async fn on_ide_chat(&mut self, mut message: ChatMessage) -> Result<()> {
+        if let Some(anchor) = message.anchor {
+            ensure!(
+                self.branch.is_none(),
+                "Cannot anchor a message in a private branch"
+            );
+            let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
+            let anchor = match self.format {
+                Format::Bytes => file.byte_to_char_offset(anchor)?,
+                Format::Chars => anchor,
+            };
+            ensure!(anchor <= file.len_chars() as u64, "Invalid chat anchor");
+            let anchor = transform_index(&self.ide_sent_delta, anchor);
+            message.anchor = Some(transform_index(&self.ide_unsent_delta, anchor));
+        }
+        message.rev_num = Some(self.rev_num);
+        if let Some(key) = &self.key {
+            message.sealed = Some(key.seal(message.text.as_bytes()));
+            message.text.clear();
+        }
+        self.server.send(MessageServer::Chat(message)).await
+    }
+
+    async fn on_server_chat(&mut self, mut message: ChatMessage) -> Result<()> {
+        if let Some(text) = self.open_sealed(message.sealed.take().as_deref(), |key, sealed| {
+            Ok(String::from_utf8(key.open(sealed)?)?)
+        })? {
+            message.text = text;
+        }
+        if let Some(mut anchor) = message.anchor {
+            if message.rev_num == Some(self.rev_num) {
+                anchor = transform_index(&self.server_sent_delta, anchor);
+                anchor = transform_index(&self.server_unsent_delta, anchor);
+            }
+            message.anchor = Some(self.offset_to_ide(anchor)?);
+        }
+        message.rev_num = None;
+        self.ide.send(MessageIde::Chat(message)).await;
+        Ok(())
+    }
+
+    async fn on_ide_create_thread(&mut self, mut new_thread: NewThread) -> Result<()> {
+        ensure!(
+            self.branch.is_none(),
+            "Cannot create a thread in a private branch"
+        );
+        let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
         if matches!(self.format, Format::Bytes) {
-            if let Err(_) = file.char_to_byte_cursor(&mut cursor_info) {
-                return Ok(());
+            new_thread.start = file.byte_to_char_offset(new_thread.start)?;
+            new_thread.end = file.byte_to_char_offset(new_thread.end)?;
+        }
+        ensure!(
+            new_thread.start <= new_thread.end && new_thread.end <= file.len_chars() as u64,
+            "Invalid thread range"
+        );
+        for delta in [&self.ide_sent_delta, &self.ide_unsent_delta] {
+            new_thread.start = transform_index(delta, new_thread.start);
+            new_thread.end = transform_index(delta, new_thread.end);
+        }
+        self.seal_comment(&mut new_thread.comment);
+        if !self.server_unsent_delta.is_noop() {
+            self.pending_threads.push(new_thread);
+            return Ok(());
+        }
+        self.send_thread(new_thread).await
+    }
+
+    async fn send_thread(&mut self, mut new_thread: NewThread) -> Result<()> {
+        new_thread.rev_num = Some(self.rev_num);
+        self.server
+            .send(MessageServer::CreateThread(new_thread))
+            .await
+    }
+
+    fn seal_comment(&self, comment: &mut Comment) {
+        if let Some(key) = &self.key {
+            comment.sealed = Some(key.seal(comment.text.as_bytes()));
+            comment.text.clear();
+        }
+    }
+
+    fn thread_to_ide(&self, thread: &mut Thread) -> Result<()> {
+        for comment in thread.comments.iter_mut() {
+            if let Some(text) = self
+                .open_sealed(comment.sealed.take().as_deref(), |key, sealed| {
+                    Ok(String::from_utf8(key.open(sealed)?)?)
+                })?
+            {
+                comment.text = text;
             }
         }
-        self.ide.send(MessageIde::Cursor(cursor_info)).await;
+        if thread.rev_num.take() == Some(self.rev_num) {
+            for delta in [&self.server_sent_delta, &self.server_unsent_delta] {
+                thread.start = transform_index(delta, thread.start);
+                thread.end = transform_index(delta, thread.end);
+            }
+        }
+        thread.start = self.offset_to_ide(thread.start)?;
+        thread.end = self.offset_to_ide(thread.end)?;
+        Ok(())
+    }
+
+    async fn on_server_thread_updated(&mut self, mut thread: Thread) -> Result<()> {
+        self.thread_to_ide(&mut thread)?;
+        self.ide.send(MessageIde::ThreadUpdated(thread)).await;
+        Ok(())
+    }
+
+    async fn on_server_threads(&mut self, mut threads: Vec<Thread>) -> Result<()> {
+        for thread in threads.iter_mut() {
+            self.thread_to_ide(thread)?;
+        }
+        self.ide.send(MessageIde::Threads { threads }).await;
+        Ok(())
+    }
+
+    fn activity(&self, now: Instant) -> Activity {
+        if self.detached {
+            Activity::Away
+        } else if self
+            .last_change
+            .is_some_and(|last_change| now.duration_since(last_change) < TYPING_DELAY)
+        {
+            Activity::Typing
+        } else if now.duration_since(self.last_input) >= self.idle_after {
+            Activity::Idle
+        } else {
+            Activity::Active
+        }
+    }
+
+    /// Publishes the activity of the local participant when it changed.
+    pub async fn on_tick(&mut self, now: Instant) {
+        let activity = self.activity(now);
+        if self.activity != Some(activity) {
+            self.activity = Some(activity);
+            let _ = self
+                .server
+                .send(MessageServer::Activity(ActivityInfo { id: None, activity }))
+                .await;
+        }
+    }
+
+    /// Delta bringing the merged document back to the document currently displayed by the ide.
+    fn ide_revert(&self, file: &File) -> Result<Option<OperationSeq>> {
+        // The ide has not applied the pending changes yet, it will move the positions itself
+        let ide_pending = self.ide_sent_delta.compose(&self.ide_unsent_delta)?;
+        if ide_pending.is_noop() {
+            return Ok(None);
+        }
+        Ok(Some(ide_pending.invert(&file.to_string())))
+    }
+
+    fn offset_to_ide(&self, mut offset: u64) -> Result<u64> {
+        let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
+        for delta in self.to_branch()? {
+            offset = transform_index(&delta, offset);
+        }
+        if let Some(ide_revert) = self.ide_revert(file)? {
+            offset = transform_index(&ide_revert, offset);
+        }
+        offset = offset.min(file.len_chars() as u64);
+        match self.format {
+            Format::Bytes => file.char_to_byte_offset(offset),
+            Format::Chars => Ok(offset),
+        }
+    }
+
+    fn cursors_to_ide(&self, cursors: &mut Vec<Cursor>) -> Result<()> {
+        let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
+        for delta in self.to_branch()? {
+            transform_cursors(&delta, cursors);
+        }
+        if let Some(ide_revert) = self.ide_revert(file)? {
+            transform_cursors(&ide_revert, cursors);
+        }
+        let len = file.len_chars() as u64;
+        for cursor in cursors.iter_mut() {
+            cursor.cursor = cursor.cursor.min(len);
+            cursor.anchor = cursor.anchor.min(len);
+        }
+        if matches!(self.format, Format::Bytes) {
+            let mut cursor_info = CursorsInfo {
+                id: None,
+                cursors: std::mem::take(cursors),
+                rev_num: None,
+            };
+            file.char_to_byte_cursor(&mut cursor_info)?;
+            *cursors = cursor_info.cursors;
+        }
+        Ok(())
+    }
+
+    fn viewports_to_ide(&self, viewports: &mut [Viewport]) -> Result<()> {
+        let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
+        for delta in self.to_branch()? {
+            transform_viewports(&delta, viewports);
+        }
+        if let Some(ide_revert) = self.ide_revert(file)? {
+            transform_viewports(&ide_revert, viewports);
+        }
+        let len = file.len_chars();
+        for viewport in viewports.iter_mut() {
+            let start = (viewport.start as usize).min(len);
+            let end = (viewport.end as usize).min(len);
+            // A range ending within a line still shows that line
+            let mut end_line = file.char_to_line(end);
+            if file.line_to_char(end_line) != end {
+                end_line += 1;
+            }
+            viewport.start = file.char_to_line(start) as u64;
+            viewport.end = end_line.max(file.char_to_line(start)) as u64;
+        }
+        Ok(())
+    }
+
+    /// Whether a cursor move is the one the ide makes when applying the last `ScrollTo` or
+    /// `Reveal`, each hint being expected to move the cursor only once.
+    fn is_hinted_move(&mut self, cursors: &[Cursor]) -> bool {
+        let Some(position) = cursors.first().map(|cursor| cursor.cursor) else {
+            return false;
+        };
+        if self.scrolled_to == Some(position) {
+            // The cursor is set last, so a reveal that did not move it is not waited for anymore
+            self.scrolled_to = None;
+            self.revealed = None;
+            return true;
+        }
+        // Revealing a viewport brings a cursor outside of it to some line within
+        let line = self.ide_line(position).ok();
+        let in_revealed = self.revealed.is_some_and(|viewport| {
+            line.is_some_and(|line| {
+                (viewport.start..viewport.end.max(viewport.start + 1)).contains(&line)
+            })
+        });
+        if in_revealed {
+            self.revealed = None;
+        }
+        in_revealed
+    }
+
+    /// Line of an offset given by the ide.
+    fn ide_line(&self, offset: u64) -> Result<u64> {
+        let file = self.file.as_ref().ok_or_else(|| anyhow!("File not set"))?;
+        let offset = match self.format {
+            Format::Bytes => file.byte_to_char_offset(offset)?,
+            Format::Chars => offset,
+        };
+        ensure!(offset <= file.len_chars() as u64, "Invalid offset");
+        Ok(file.char_to_line(offset as usize) as u64)
+    }
+
+    async fn scroll_to(&mut self, id: usize, cursors: &[Cursor]) {
+        if let Some(cursor) = cursors.first() {
+            self.scrolled_to = Some(cursor.cursor);
+            self.ide
+                .send(MessageIde::ScrollTo {
+                    id,
+                    offset: cursor.cursor,
+                })
+                .await;
+        }
+    }
+
+    async fn reveal(&mut self, id: usize, viewports: &[Viewport]) {
+        if let Some(&viewport) = viewports.first() {
+            self.revealed = Some(viewport);
+            self.ide.send(MessageIde::Reveal { id, viewport }).await;
+        }
+    }
+
+    async fn on_follow(&mut self, id: Option<usize>) -> Result<()> {
+        if id == Some(self.client_id) {
+            bail!("Cannot follow yourself");
+        }
+        self.following = id;
+        self.scrolled_to = None;
+        self.revealed = None;
+        if let Some(id) = id {
+            self.jump_to(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Shows the last known viewport and cursor of a participant.
+    async fn jump_to(&mut self, id: usize) -> Result<()> {
+        if let Some(mut viewports) = self.remote_viewports.get(&id).cloned() {
+            self.viewports_to_ide(&mut viewports)?;
+            self.reveal(id, &viewports).await;
+        }
+        if let Some(mut cursors) = self.remote_cursors.get(&id).cloned() {
+            self.cursors_to_ide(&mut cursors)?;
+            self.scroll_to(id, &cursors).await;
+        }
         Ok(())
     }
 
+    /// The presenter's view is imposed on the other participants, unless they opted out.
+    fn watches(&self, id: usize) -> bool {
+        self.watch_presenter && self.presenter == Some(id) && id != self.client_id
+    }
+
+    async fn on_presenter_changed(&mut self, id: Option<usize>) -> Result<()> {
+        self.presenter = id;
+        self.ide.send(MessageIde::PresenterChanged { id }).await;
+        match id {
+            Some(id) if self.watches(id) => self.jump_to(id).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_watch_presenter(&mut self, enabled: bool) -> Result<()> {
+        self.watch_presenter = enabled;
+        match self.presenter {
+            Some(id) if self.watches(id) => self.jump_to(id).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn end_follow(&mut self) {
+        if let Some(id) = self.following.take() {
+            self.scrolled_to = None;
+            self.revealed = None;
+            self.ide.send(MessageIde::FollowEnded { id }).await;
+        }
+    }
+
     pub async fn on_message_server(&mut self, message: MessageServer) {
+        let journaled = matches!(
+            message,
+            MessageServer::ServerUpdate(_)
+                | MessageServer::Ack
+                | MessageServer::Resumed
+                | MessageServer::File { .. }
+        );
         let res = match message {
             MessageServer::ServerUpdate(modif) => self.on_server_change(&modif).await,
             MessageServer::Ack => self.on_ack().await,
             MessageServer::Error { error: err } => Err(anyhow!(err)),
             MessageServer::RequestFile => self.on_request_file().await,
-            MessageServer::File { file, version } => self.on_receive_file(file, version).await,
+            MessageServer::File {
+                file,
+                version,
+                sealed,
+            } => self.on_receive_file(file, version, sealed).await,
             MessageServer::Cursor(cursor_info) => self.on_server_cursor_move(cursor_info).await,
+            MessageServer::Viewport(viewport_info) => {
+                self.on_server_viewport_move(viewport_info).await
+            }
+            MessageServer::Chat(message) => self.on_server_chat(message).await,
+            MessageServer::ThreadUpdated(thread) => self.on_server_thread_updated(thread).await,
+            MessageServer::Threads { threads } => self.on_server_threads(threads).await,
+            MessageServer::Checkpoints { checkpoints } => {
+                self.ide.send(MessageIde::Checkpoints { checkpoints }).await;
+                Ok(())
+            }
+            MessageServer::Diff(diff) => {
+                self.ide.send(MessageIde::Diff(diff)).await;
+                Ok(())
+            }
+            MessageServer::Blame { ranges } => {
+                self.ide.send(MessageIde::Blame { ranges }).await;
+                Ok(())
+            }
+            MessageServer::Revision { rev_num, file } => {
+                self.ide.send(MessageIde::Revision { rev_num, file }).await;
+                Ok(())
+            }
+            MessageServer::Audit { entries } => {
+                self.ide.send(MessageIde::Audit { entries }).await;
+                Ok(())
+            }
+            MessageServer::Welcome { id, session } => {
+                self.client_id = id;
+                self.session = Some(session);
+                self.ide.send(MessageIde::Welcome { id }).await;
+                Ok(())
+            }
+            MessageServer::Roster { participants } => {
+                self.ide.send(MessageIde::Roster { participants }).await;
+                Ok(())
+            }
+            MessageServer::PresenterChanged { id } => self.on_presenter_changed(id).await,
+            MessageServer::Activity(activity_info) => {
+                self.ide.send(MessageIde::Activity(activity_info)).await;
+                Ok(())
+            }
+            MessageServer::Joined(participant) => {
+                self.ide.send(MessageIde::Joined(participant)).await;
+                Ok(())
+            }
+            MessageServer::Resumed => self.on_resumed().await,
+            MessageServer::Left { id } => {
+                self.remote_cursors.remove(&id);
+                self.remote_viewports.remove(&id);
+                if self.following == Some(id) {
+                    self.end_follow().await;
+                }
+                self.ide.send(MessageIde::ClearCursors { id }).await;
+                self.ide.send(MessageIde::Left { id }).await;
+                Ok(())
+            }
+            _ => {
+                warn!("Server sent unexpected message: {:?}", message);
+                Err(anyhow!("Unexpected message type: {:?}", message))
+            }
+        };
+        let res = match res {
+            Ok(()) if journaled => self.write_journal().await,
+            res => res,
         };
 
         if let Err(err) = res {
@@ -283,16 +1169,98 @@ impl Client {
     }
 
     pub async fn on_message_ide(&mut self, message_ide: MessageIde) {
+        let now = Instant::now();
+        match message_ide {
+            MessageIde::Update { .. } => {
+                self.last_change = Some(now);
+                self.last_input = now;
+            }
+            MessageIde::Cursor(_) | MessageIde::Viewport(_) | MessageIde::Chat(_) => {
+                self.last_input = now;
+            }
+            _ => (),
+        }
+
+        let journaled = matches!(
+            message_ide,
+            MessageIde::Update { .. } | MessageIde::Undo | MessageIde::Redo | MessageIde::Merge
+        );
         let res = match message_ide {
             MessageIde::Update { changes } => self.on_ide_change(changes).await,
             MessageIde::File { file } => self.on_ide_file(file).await,
             MessageIde::Ack => self.on_ide_ack().await,
             MessageIde::Cursor(cursor_info) => self.on_ide_cursor_move(cursor_info).await,
+            MessageIde::Viewport(viewport_info) => self.on_ide_viewport_move(viewport_info).await,
+            MessageIde::Chat(message) => self.on_ide_chat(message).await,
+            MessageIde::CreateThread(new_thread) => self.on_ide_create_thread(new_thread).await,
+            MessageIde::Reply {
+                thread,
+                mut comment,
+            } => {
+                self.seal_comment(&mut comment);
+                self.server
+                    .send(MessageServer::Reply { thread, comment })
+                    .await
+            }
+            MessageIde::Resolve { thread } => {
+                self.server.send(MessageServer::Resolve { thread }).await
+            }
+            MessageIde::ListThreads => self.server.send(MessageServer::ListThreads).await,
+            MessageIde::Present { enabled } => {
+                self.server.send(MessageServer::Present { enabled }).await
+            }
+            MessageIde::WatchPresenter { enabled } => self.on_watch_presenter(enabled).await,
+            MessageIde::Undo => self.on_undo(false).await,
+            MessageIde::Redo => self.on_undo(true).await,
+            MessageIde::Fork => self.on_fork().await,
+            MessageIde::Merge => self.on_merge().await,
+            MessageIde::DiscardFork => self.on_discard_fork().await,
+            MessageIde::Detach => {
+                self.detached = true;
+                self.on_tick(now).await;
+                Ok(())
+            }
+            MessageIde::Attach => {
+                self.detached = false;
+                self.last_input = now;
+                self.on_tick(now).await;
+                Ok(())
+            }
+            MessageIde::Checkpoint { name } => {
+                self.server.send(MessageServer::Checkpoint { name }).await
+            }
+            MessageIde::Revert { rev_num } => {
+                self.server.send(MessageServer::Revert { rev_num }).await
+            }
+            MessageIde::RevertToCheckpoint { name } => {
+                self.server
+                    .send(MessageServer::RevertToCheckpoint { name })
+                    .await
+            }
+            MessageIde::RequestDiff { from, to } => {
+                self.server
+                    .send(MessageServer::RequestDiff { from, to })
+                    .await
+            }
+            MessageIde::RequestBlame => self.server.send(MessageServer::RequestBlame).await,
+            MessageIde::RequestRevision { rev_num } => {
+                self.server
+                    .send(MessageServer::RequestRevision { rev_num })
+                    .await
+            }
+            MessageIde::RequestAudit(query) => {
+                self.server.send(MessageServer::RequestAudit(query)).await
+            }
+            MessageIde::Follow { id } => self.on_follow(id).await,
             _ => {
                 warn!("IDE sent bad unexpected message: {:?}", message_ide);
                 Err(anyhow!("Unexpected message type: {:?}", message_ide))
             }
         };
+        let res = match res {
+            Ok(()) if journaled => self.write_journal().await,
+            res => res,
+        };
 
         if let Err(err) = res {
             self.ide
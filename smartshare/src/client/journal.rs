@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use anyhow::Result;
+use operational_transform::OperationSeq;
+use serde::{Deserialize, Serialize};
+
+/// Local edits not yet acknowledged by the server, kept on disk to survive a crash.
+///
+/// `sent` then `unsent` apply to the server's document at `rev_num`, and give `text`. They are
+/// only resumed with the server session they were made in.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Journal {
+    pub rev_num: usize,
+    pub id: usize,
+    #[serde(default)]
+    pub session: Option<String>,
+    pub text: String,
+    pub sent: OperationSeq,
+    pub unsent: OperationSeq,
+}
+
+impl Journal {
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        // Written aside then renamed so that a crash never leaves a truncated journal
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    pub async fn clear(path: &Path) -> Result<()> {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sent.is_noop() && self.unsent.is_noop()
+    }
+}
@@ -1,23 +1,35 @@
 pub mod client;
 pub mod ide;
+pub mod journal;
 pub mod server;
 
 use core::panic;
-use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use futures::SinkExt;
-use smartshare::protocol::msg::{MessageIde, MessageServer, Format};
+use futures::{SinkExt, Stream};
+use smartshare::crypto::{self, SessionKey};
+use smartshare::invite::{local_host, Invite, DEFAULT_PORT};
+use smartshare::protocol::msg::{JoinRequest, MessageIde, MessageServer, Format};
 use smartshare::protocol::{message_sink, message_stream};
-use tokio::net::TcpStream;
+use smartshare::server::client::Client as SessionClient;
+use smartshare::server::connection::{self, Access};
+use smartshare::server::limiter::RateLimits;
+use smartshare::server::metrics::Metrics;
+use smartshare::server::server::Server as SessionServer;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use self::client::Client;
 use self::ide::Ide;
+use self::journal::Journal;
 use self::server::Server;
 
 #[derive(Parser, Debug)]
@@ -28,13 +40,139 @@ struct Args {
     format: Format,
 
 
-    /// ipv4 adress of the server
-    ip: SocketAddrV4,
+    /// secret shared by the participants to encrypt the session end-to-end, made with
+    /// --generate-secret
+    #[arg(long)]
+    secret: Option<SessionKey>,
+
+    /// print a new secret for --secret and exit
+    #[arg(long, exclusive = true)]
+    generate_secret: bool,
+
+    /// name shown to the other participants
+    #[arg(long)]
+    name: Option<String>,
+
+    /// preferred color of this participant's cursors, such as #4682B4
+    #[arg(long)]
+    color: Option<String>,
+
+    /// seconds without input after which this participant is shown as idle
+    #[arg(long, default_value_t = 60)]
+    idle_after: u64,
+
+    /// file keeping the edits not yet acknowledged by the server, offered again after a crash
+    #[arg(long, conflicts_with = "host")]
+    journal: Option<PathBuf>,
+
+    /// host the session by running the server inside this client
+    #[arg(long)]
+    host: bool,
+
+    /// port the guests connect to when hosting
+    #[arg(short, long, default_value_t = DEFAULT_PORT, requires = "host")]
+    port: u16,
+
+    /// token the guests must present when hosting
+    #[arg(long, requires = "host")]
+    token: Option<String>,
+
+    /// address of the server, or an invite such as smartshare://host:port/room?token=...
+    #[arg(required_unless_present_any = ["host", "generate_secret"], conflicts_with = "host")]
+    invite: Option<Invite>,
+}
+
+type ServerStream = Pin<Box<dyn Stream<Item = anyhow::Result<MessageServer>> + Send>>;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+async fn connect(
+    invite: Invite,
+    join: JoinRequest,
+    server_receiver: mpsc::Receiver<MessageServer>,
+) -> anyhow::Result<ServerStream> {
+    if invite.fingerprint.is_some() {
+        anyhow::bail!("This invite requires TLS, which this client does not support");
+    }
+
+    let binding: TcpStream = TcpStream::connect((invite.host.as_str(), invite.port)).await?;
+
+    let (rx, tx) = tokio::io::split(binding);
+
+    let join = MessageServer::Join(JoinRequest {
+        room: invite.room,
+        token: invite.token,
+        ..join
+    });
+    tokio::spawn(async move {
+        let mut tcp_sink = message_sink::<MessageServer, _>(tx);
+        if tcp_sink.send(join).await.is_err() {
+            return;
+        }
+        let mut stream = ReceiverStream::new(server_receiver).map(Ok);
+        // A lost connection is noticed by the reading side
+        let _ = tcp_sink.send_all(&mut stream).await;
+    });
+
+    Ok(Box::pin(message_stream::<MessageServer, _>(rx)))
+}
+
+async fn host(
+    port: u16,
+    token: Option<String>,
+    join: JoinRequest,
+    server_receiver: mpsc::Receiver<MessageServer>,
+    ide: &mut Ide,
+) -> anyhow::Result<ServerStream> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|err| anyhow::anyhow!("Cannot host on port {port}: {err}"))?;
+    let (mut server, handle) = SessionServer::new();
+    tokio::spawn(async move {
+        server.run().await;
+    });
+
+    let mut invite = Invite::new(local_host().unwrap_or_else(|| "127.0.0.1".into()), port);
+    invite.token = token.clone();
+    info!("Hosting session, invite: {}", invite.redacted());
+    // Stdout belongs to the ide, which hands the invite to the host
+    ide.send(MessageIde::Invite {
+        invite: invite.to_string(),
+    })
+    .await;
+
+    tokio::spawn(connection::accept(
+        listener,
+        handle.clone(),
+        Access { room: None, token },
+        RateLimits::default(),
+        Arc::new(Metrics::default()),
+    ));
+
+    // The local client talks to the server through channels instead of a socket
+    let id = handle.next_id();
+    let (tx, rx) = mpsc::channel(8);
+    handle
+        .on_connect(SessionClient::new(id, "host".into(), tx), join)
+        .await;
+    tokio::spawn(async move {
+        let mut messages = ReceiverStream::new(server_receiver);
+        while let Some(message) = messages.next().await {
+            handle.on_message(id, message).await;
+        }
+        handle.on_disconnect(id).await;
+    });
+
+    Ok(Box::pin(ReceiverStream::new(rx).map(Ok)))
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    if args.generate_secret {
+        println!("{}", crypto::generate_secret());
+        return;
+    }
 
     let subscriber = tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
@@ -42,40 +180,82 @@ async fn main() {
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let binding: TcpStream = match TcpStream::connect(args.ip).await {
-        Ok(stream) => {
-            stream
-        }
-        Err(err) => {
-            error!("{err}");
-            panic!("{err}");
-        }
-    };
-
-    let (rx, tx) = tokio::io::split(binding);
-
     let (ide_sender, ide_receiver) = mpsc::channel(8);
-    let ide = Ide::new(ide_sender);
-
-    let (server_sender, server_receiver) = mpsc::channel(8);
-    let server = Server::new(server_sender);
+    let mut ide = Ide::new(ide_sender);
 
-    let mut client = Client::new(server, ide, 0, args.format);
-
-    tokio::spawn(async move {
+    let stdout = tokio::spawn(async move {
         let mut stdout_sink = message_sink::<MessageIde, _>(tokio::io::stdout());
         let mut stream = ReceiverStream::new(ide_receiver).map(Ok);
         stdout_sink.send_all(&mut stream).await.unwrap();
     });
 
-    tokio::spawn(async move {
-        let mut tcp_sink = message_sink::<MessageServer, _>(tx);
-        let mut stream = ReceiverStream::new(server_receiver).map(Ok);
-        tcp_sink.send_all(&mut stream).await.unwrap();
-    });
-
     let mut stdin_stream = message_stream::<MessageIde, _>(tokio::io::stdin());
-    let mut tcp_stream = message_stream::<MessageServer, _>(rx);
+
+    let (server_sender, server_receiver) = mpsc::channel(8);
+    let server = Server::new(server_sender);
+
+    let mut client = Client::new(server, ide.clone(), 0, args.format)
+        .with_idle_after(Duration::from_secs(args.idle_after));
+    if let Some(key) = args.secret.clone() {
+        client = client.with_session_key(key);
+    }
+    if let Some(path) = &args.journal {
+        client = client.with_journal(path.clone());
+        match Journal::load(path).await {
+            Ok(Some(journal)) if !journal.is_empty() => {
+                // The edits left by a crashed run are only sent again if the user wants them
+                ide.send(MessageIde::JournalFound {
+                    rev_num: journal.rev_num,
+                })
+                .await;
+                let replay = loop {
+                    match stdin_stream.next().await {
+                        Some(Ok(MessageIde::ReplayJournal { replay })) => break replay,
+                        Some(Ok(message)) => {
+                            warn!("Ignoring {message:?} until the journal is replayed or dropped")
+                        }
+                        _ => return,
+                    }
+                };
+                if replay {
+                    if let Err(err) = client.restore(journal).await {
+                        error!("Could not replay the journal: {err}");
+                    }
+                }
+            }
+            Ok(_) => (),
+            Err(err) => warn!("Could not read the journal: {err}"),
+        }
+    }
+
+    let join = JoinRequest {
+        name: args.name,
+        color: args.color,
+        resume: client.resume(),
+        ..Default::default()
+    };
+    let stream = match args.invite.clone() {
+        Some(invite) => connect(invite, join.clone(), server_receiver).await,
+        None => host(args.port, args.token, join.clone(), server_receiver, &mut ide).await,
+    };
+    let mut tcp_stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("{err}");
+            ide.send(MessageIde::Error {
+                error: err.to_string(),
+            })
+            .await;
+            // The error reaches the ide once every sender is gone
+            drop((client, ide));
+            let _ = stdout.await;
+            return;
+        }
+    };
+
+    let mut activity_tick = tokio::time::interval(Duration::from_millis(500));
+    let mut reconnect_tick = tokio::time::interval(RECONNECT_DELAY);
+    let mut connected = true;
 
     loop {
         select! {
@@ -94,20 +274,48 @@ async fn main() {
                     },
                 }
             }
-            message_opt = tcp_stream.next() => {
+            message_opt = tcp_stream.next(), if connected => {
                 match message_opt {
                     Some(Ok(message)) => {
                         client.on_message_server(message).await;
+                        continue;
                     },
                     Some(Err(err)) => {
                         error!("Error while reading tcp_stream: {}", err);
-                        break;
                     },
                     None => {
                         error!("End of tcp stream");
-                        break;
                     },
                 }
+                // Only a guest can get its connection back, the edits made meanwhile wait for it
+                if args.invite.is_none() {
+                    break;
+                }
+                connected = false;
+                client.on_disconnect().await;
+                reconnect_tick.reset();
+            }
+            _ = reconnect_tick.tick(), if !connected => {
+                let Some(invite) = args.invite.clone() else {
+                    break;
+                };
+                let (server_sender, server_receiver) = mpsc::channel(8);
+                let join = JoinRequest {
+                    resume: client.resume(),
+                    ..join.clone()
+                };
+                match connect(invite, join, server_receiver).await {
+                    Ok(stream) => {
+                        info!("Reconnected to the server");
+                        tcp_stream = stream;
+                        connected = true;
+                        client.on_reconnect(Server::new(server_sender)).await;
+                    }
+                    Err(err) => warn!("Could not reconnect: {err}"),
+                }
+            }
+            _ = activity_tick.tick() => {
+                client.on_tick(Instant::now()).await;
             }
         }
     }
@@ -115,123 +323,1062 @@ async fn main() {
 
 #[cfg(test)]
 mod test {
+    use std::time::{Duration, Instant};
+
     use operational_transform::OperationSeq;
     use smartshare::protocol::msg::{
-        Format, MessageIde, MessageServer, ModifRequest, TextModification,
+        Activity, ActivityInfo, ChatMessage, Comment, Cursor, CursorsInfo, Format, MessageIde, MessageServer, ModifRequest, NewThread,
+        Resume, TextModification, Viewport, ViewportInfo,
     };
 
+    use smartshare::crypto::{generate_secret, SessionKey, MASK};
+
     use crate::client::Client;
     use crate::ide::Ide;
+    use crate::journal::Journal;
     use crate::server::Server;
 
     #[tokio::test]
-    async fn simple_connection() {
-        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
+    async fn simple_connection() {
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 0,
+                sealed: None,
+            })
+            .await;
+
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::File {
+                file: "Hello world".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn participant_left() {
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client.on_message_server(MessageServer::Left { id: 3 }).await;
+
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::ClearCursors { id: 3 }));
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Left { id: 3 }));
+    }
+
+    #[tokio::test]
+    async fn first_connection() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client.on_message_server(MessageServer::RequestFile).await;
+
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::RequestFile));
+
+        client
+            .on_message_ide(MessageIde::File {
+                file: "Hello world".into(),
+            })
+            .await;
+
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::File {
+                file: "Hello world".into(),
+                version: 0,
+                sealed: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn ide_change_chars() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        // simple connexion with format decl
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "çalùt monde".into(),
+                version: 4,
+                sealed: None,
+            })
+            .await;
+
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::File {
+                file: "çalùt monde".into()
+            })
+        );
+
+        // ide change
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![
+                    TextModification {
+                        offset: 0,
+                        delete: 1,
+                        text: "Ç".into(),
+                    },
+                    TextModification {
+                        offset: 6,
+                        delete: 1,
+                        text: "M".into(),
+                    },
+                ],
+            })
+            .await;
+
+        let mut server_modif = OperationSeq::default();
+        server_modif.insert("Ç");
+        server_modif.delete(1);
+        server_modif.retain(5);
+        server_modif.insert("M");
+        server_modif.delete(1);
+        server_modif.retain(4);
+
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: server_modif,
+                rev_num: 4,
+                sealed: None,
+            }))
+        );
+
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+
+        // server ack
+
+        client.on_message_server(MessageServer::Ack).await;
+    }
+
+    #[tokio::test]
+    async fn cursors_concurrent_changes() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        // local change not yet acknowledged by the server
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
+            })
+            .await;
+        let _ = server_receiver.try_recv();
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+
+        // concurrent change not yet acknowledged by the ide
+
+        let mut server_modif = OperationSeq::default();
+        server_modif.insert("Oh ");
+        server_modif.retain(11);
+        client
+            .on_message_server(MessageServer::ServerUpdate(ModifRequest {
+                delta: server_modif,
+                rev_num: 3,
+                sealed: None,
+            }))
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        // "w" of "Oh Hello world" is "w" of "Big Hello world" for the ide
+
+        client
+            .on_message_server(MessageServer::Cursor(CursorsInfo {
+                id: Some(1),
+                cursors: vec![Cursor {
+                    cursor: 9,
+                    anchor: 9,
+                }],
+                rev_num: Some(3),
+            }))
+            .await;
+
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Cursor(CursorsInfo {
+                id: Some(1),
+                cursors: vec![Cursor {
+                    cursor: 10,
+                    anchor: 10,
+                }],
+                rev_num: None,
+            }))
+        );
+
+        // "H" of "Big Hello world" is "H" of "Oh Big Hello world" for the server
+
+        client
+            .on_message_ide(MessageIde::Cursor(CursorsInfo {
+                id: None,
+                cursors: vec![Cursor {
+                    cursor: 4,
+                    anchor: 4,
+                }],
+                rev_num: None,
+            }))
+            .await;
+
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::Cursor(CursorsInfo {
+                id: None,
+                cursors: vec![Cursor {
+                    cursor: 7,
+                    anchor: 7,
+                }],
+                rev_num: Some(3),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn undo_own_changes() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        client.on_message_ide(MessageIde::Undo).await;
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Error {
+                error: "Nothing to undo".into()
+            })
+        );
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
+            })
+            .await;
+        let _ = server_receiver.try_recv();
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+        client.on_message_server(MessageServer::Ack).await;
+
+        // the others' changes are kept
+
+        let mut server_modif = OperationSeq::default();
+        server_modif.insert("Oh ");
+        server_modif.retain(15);
+        client
+            .on_message_server(MessageServer::ServerUpdate(ModifRequest {
+                delta: server_modif,
+                rev_num: 4,
+                sealed: None,
+            }))
+            .await;
+        let _ = ide_receiver.try_recv();
+        client.on_message_ide(MessageIde::Ack).await;
+
+        client.on_message_ide(MessageIde::Undo).await;
+
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 3,
+                    delete: 4,
+                    text: "".into(),
+                }]
+            })
+        );
+        let mut undo_modif = OperationSeq::default();
+        undo_modif.retain(3);
+        undo_modif.delete(4);
+        undo_modif.retain(11);
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: undo_modif,
+                rev_num: 4,
+                sealed: None,
+            }))
+        );
+        client.on_message_ide(MessageIde::Ack).await;
+
+        client.on_message_ide(MessageIde::Redo).await;
+
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 3,
+                    delete: 0,
+                    text: "Big ".into(),
+                }]
+            })
+        );
+        client.on_message_ide(MessageIde::Ack).await;
+
+        client.on_message_ide(MessageIde::Redo).await;
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Error {
+                error: "Nothing to redo".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_changes() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::Welcome {
+                id: 0,
+                session: "session".into(),
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        // sent but the connection drops before the ack
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
+            })
+            .await;
+        let _ = server_receiver.try_recv();
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+
+        client.on_disconnect().await;
+        let _ = ide_receiver.try_recv();
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 15,
+                    delete: 0,
+                    text: "!".into(),
+                }],
+            })
+            .await;
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+        assert_eq!(
+            client.resume(),
+            Some(Resume {
+                rev_num: 2,
+                id: 0,
+                session: "session".into(),
+            })
+        );
+
+        // the server missed both changes and went on meanwhile
+
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        client.on_reconnect(Server::new(server_sender)).await;
+        let mut server_modif = OperationSeq::default();
+        server_modif.insert("Oh ");
+        server_modif.retain(11);
+        client
+            .on_message_server(MessageServer::ServerUpdate(ModifRequest {
+                delta: server_modif,
+                rev_num: 3,
+                sealed: None,
+            }))
+            .await;
+        assert!(server_receiver.try_recv().is_err());
+        client.on_message_server(MessageServer::Resumed).await;
+
+        let mut rebased = OperationSeq::default();
+        rebased.retain(3);
+        rebased.insert("Big ");
+        rebased.retain(11);
+        rebased.insert("!");
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: rebased,
+                rev_num: 3,
+                sealed: None,
+            }))
+        );
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Oh ".into(),
+                }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn acked_while_resuming() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        // reaches the server just before the connection drops
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
+            })
+            .await;
+        let _ = server_receiver.try_recv();
+        let _ = ide_receiver.try_recv();
+
+        client.on_disconnect().await;
+        let _ = ide_receiver.try_recv();
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 15,
+                    delete: 0,
+                    text: "!".into(),
+                }],
+            })
+            .await;
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        client.on_reconnect(Server::new(server_sender)).await;
+        client.on_message_server(MessageServer::Ack).await;
+        assert!(server_receiver.try_recv().is_err());
+        client.on_message_server(MessageServer::Resumed).await;
+
+        let mut offline = OperationSeq::default();
+        offline.retain(15);
+        offline.insert("!");
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: offline,
+                rev_num: 3,
+                sealed: None,
+            }))
+        );
+        assert!(server_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn thread_after_pending_edits() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+        for (offset, text) in [(0, "Big "), (15, "!")] {
+            client
+                .on_message_ide(MessageIde::Update {
+                    changes: vec![TextModification {
+                        offset,
+                        delete: 0,
+                        text: text.into(),
+                    }],
+                })
+                .await;
+            let _ = ide_receiver.try_recv();
+        }
+        let _ = server_receiver.try_recv();
+
+        // the range covers the unsent "!", so the server must get it first
+
+        client
+            .on_message_ide(MessageIde::CreateThread(NewThread {
+                start: 4,
+                end: 16,
+                comment: Comment {
+                    text: "Loud".into(),
+                    ..Default::default()
+                },
+                rev_num: None,
+            }))
+            .await;
+        assert!(server_receiver.try_recv().is_err());
+
+        let mut remote = OperationSeq::default();
+        remote.insert("Oh ");
+        remote.retain(11);
+        client
+            .on_message_server(MessageServer::ServerUpdate(ModifRequest {
+                delta: remote,
+                rev_num: 3,
+                sealed: None,
+            }))
+            .await;
+        let _ = ide_receiver.try_recv();
+        client.on_message_server(MessageServer::Ack).await;
+
+        let mut unsent = OperationSeq::default();
+        unsent.retain(18);
+        unsent.insert("!");
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: unsent,
+                rev_num: 4,
+                sealed: None,
+            }))
+        );
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::CreateThread(NewThread {
+                start: 7,
+                end: 19,
+                comment: Comment {
+                    text: "Loud".into(),
+                    ..Default::default()
+                },
+                rev_num: Some(4),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_refused() {
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+        client.on_disconnect().await;
+        let _ = ide_receiver.try_recv();
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        // the server restarted with another document
+
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        client.on_reconnect(Server::new(server_sender)).await;
+        client
+            .on_message_server(MessageServer::File {
+                file: "Other".into(),
+                version: 4,
+                sealed: None,
+            })
+            .await;
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::File {
+                file: "Other".into()
+            })
+        );
+        assert!(matches!(ide_receiver.try_recv(), Ok(MessageIde::Error { .. })));
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 5,
+                    delete: 0,
+                    text: "!".into(),
+                }],
+            })
+            .await;
+        let mut delta = OperationSeq::default();
+        delta.retain(5);
+        delta.insert("!");
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta,
+                rev_num: 4,
+                sealed: None,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_journal() {
+        let path = std::env::temp_dir().join(format!("smartshare-journal-{}.json", std::process::id()));
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client =
+            Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars).with_journal(path.clone());
+
+        client
+            .on_message_server(MessageServer::Welcome {
+                id: 0,
+                session: "session".into(),
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
+            })
+            .await;
+        let _ = server_receiver.try_recv();
+
+        // the client crashes before the ack
+
+        let journal = Journal::load(&path).await.unwrap().unwrap();
+        assert_eq!(journal.rev_num, 2);
+        assert_eq!(journal.text, "Big Hello world");
+        assert_eq!(journal.session.as_deref(), Some("session"));
+
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client =
+            Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars).with_journal(path.clone());
+        client.restore(journal).await.unwrap();
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::File {
+                file: "Big Hello world".into()
+            })
+        );
+        assert_eq!(
+            client.resume(),
+            Some(Resume {
+                rev_num: 2,
+                id: 0,
+                session: "session".into(),
+            })
+        );
+
+        client.on_message_server(MessageServer::Resumed).await;
+        let mut resent = OperationSeq::default();
+        resent.insert("Big ");
+        resent.retain(11);
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: resent,
+                rev_num: 2,
+                sealed: None,
+            }))
+        );
+
+        client.on_message_server(MessageServer::Ack).await;
+        assert_eq!(Journal::load(&path).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn replay_journal_without_document() {
+        let path = std::env::temp_dir().join(format!("smartshare-new-journal-{}.json", std::process::id()));
+        let mut sent = OperationSeq::default();
+        sent.insert("Big ");
+        sent.retain(11);
+        let mut unsent = OperationSeq::default();
+        unsent.retain(15);
+        unsent.insert("!");
+        let journal = Journal {
+            rev_num: 2,
+            id: 0,
+            session: None,
+            text: "Big Hello world!".into(),
+            sent,
+            unsent,
+        };
+
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client =
+            Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars).with_journal(path.clone());
+        client.restore(journal).await.unwrap();
+        let _ = ide_receiver.try_recv();
+
+        // the server restarted without the document, the restored text becomes the new one
+
+        client.on_message_server(MessageServer::RequestFile).await;
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::RequestFile));
+        client
+            .on_message_ide(MessageIde::File {
+                file: "Big Hello world!".into(),
+            })
+            .await;
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::File {
+                file: "Big Hello world!".into(),
+                version: 0,
+                sealed: None,
+            })
+        );
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 4,
+                    text: "".into(),
+                }],
+            })
+            .await;
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+        let mut delta = OperationSeq::default();
+        delta.delete(4);
+        delta.retain(12);
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta,
+                rev_num: 0,
+                sealed: None,
+            }))
+        );
+        Journal::clear(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fork_and_merge() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
         let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
         let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
 
         client
             .on_message_server(MessageServer::File {
                 file: "Hello world".into(),
-                version: 0,
+                version: 2,
+                sealed: None,
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+        client.on_message_ide(MessageIde::Fork).await;
+
+        // the branch is kept from the others and the others' changes from the branch
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "Big ".into(),
+                }],
             })
             .await;
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
+        assert!(server_receiver.try_recv().is_err());
+
+        let mut server_modif = OperationSeq::default();
+        server_modif.retain(11);
+        server_modif.insert("!");
+        client
+            .on_message_server(MessageServer::ServerUpdate(ModifRequest {
+                delta: server_modif,
+                rev_num: 3,
+                sealed: None,
+            }))
+            .await;
+        assert!(ide_receiver.try_recv().is_err());
+
+        client
+            .on_message_server(MessageServer::Cursor(CursorsInfo {
+                id: Some(1),
+                cursors: vec![Cursor {
+                    cursor: 12,
+                    anchor: 12,
+                }],
+                rev_num: Some(3),
+            }))
+            .await;
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Cursor(CursorsInfo {
+                id: Some(1),
+                cursors: vec![Cursor {
+                    cursor: 15,
+                    anchor: 15,
+                }],
+                rev_num: None,
+            }))
+        );
+
+        client.on_message_ide(MessageIde::Merge).await;
 
+        let mut merged = OperationSeq::default();
+        merged.insert("Big ");
+        merged.retain(12);
+        assert_eq!(
+            server_receiver.try_recv(),
+            Ok(MessageServer::ServerUpdate(ModifRequest {
+                delta: merged,
+                rev_num: 3,
+                sealed: None,
+            }))
+        );
         assert_eq!(
             ide_receiver.try_recv(),
-            Ok(MessageIde::File {
-                file: "Hello world".into()
+            Ok(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 15,
+                    delete: 0,
+                    text: "!".into(),
+                }]
             })
         );
     }
 
     #[tokio::test]
-    async fn first_connection() {
-        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+    async fn follow_participant() {
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
         let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
-        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
-
-        client.on_message_server(MessageServer::RequestFile).await;
-
-        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::RequestFile));
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Bytes);
 
         client
-            .on_message_ide(MessageIde::File {
-                file: "Hello world".into(),
+            .on_message_server(MessageServer::File {
+                file: "çalùt monde".into(),
+                version: 0,
+                sealed: None,
             })
             .await;
+        let _ = ide_receiver.try_recv();
+
+        client.on_message_ide(MessageIde::Follow { id: Some(1) }).await;
+
+        let cursor_info = CursorsInfo {
+            id: Some(1),
+            cursors: vec![Cursor {
+                cursor: 6,
+                anchor: 6,
+            }],
+            rev_num: Some(0),
+        };
+        client
+            .on_message_server(MessageServer::Cursor(cursor_info))
+            .await;
 
         assert_eq!(
-            server_receiver.try_recv(),
-            Ok(MessageServer::File {
-                file: "Hello world".into(),
-                version: 0
-            })
+            ide_receiver.try_recv(),
+            Ok(MessageIde::ScrollTo { id: 1, offset: 8 })
         );
+        let _ = ide_receiver.try_recv();
+
+        // the ide moving its cursor to the hint does not end the follow
+
+        client
+            .on_message_ide(MessageIde::Cursor(CursorsInfo {
+                id: None,
+                cursors: vec![Cursor {
+                    cursor: 8,
+                    anchor: 8,
+                }],
+                rev_num: None,
+            }))
+            .await;
+
+        assert!(ide_receiver.try_recv().is_err());
+
+        // but the user moving it afterwards does, even to the same place
+
+        client
+            .on_message_ide(MessageIde::Cursor(CursorsInfo {
+                id: None,
+                cursors: vec![Cursor {
+                    cursor: 8,
+                    anchor: 8,
+                }],
+                rev_num: None,
+            }))
+            .await;
+
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::FollowEnded { id: 1 }));
     }
 
     #[tokio::test]
-    async fn ide_change_chars() {
-        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+    async fn follow_viewport() {
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
         let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
         let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
 
-        // simple connexion with format decl
-
         client
             .on_message_server(MessageServer::File {
-                file: "çalùt monde".into(),
-                version: 4,
+                file: "a\nb\nc\nd".into(),
+                version: 0,
+                sealed: None,
             })
             .await;
+        let _ = ide_receiver.try_recv();
+
+        client.on_message_ide(MessageIde::Follow { id: Some(1) }).await;
+        client
+            .on_message_server(MessageServer::Viewport(ViewportInfo {
+                id: Some(1),
+                viewports: vec![Viewport { start: 4, end: 7 }],
+                rev_num: Some(0),
+            }))
+            .await;
 
         assert_eq!(
             ide_receiver.try_recv(),
-            Ok(MessageIde::File {
-                file: "çalùt monde".into()
+            Ok(MessageIde::Reveal {
+                id: 1,
+                viewport: Viewport { start: 2, end: 4 },
             })
         );
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Viewport(ViewportInfo {
+                id: Some(1),
+                viewports: vec![Viewport { start: 2, end: 4 }],
+                rev_num: None,
+            }))
+        );
 
-        // ide change
+        // the ide bringing the cursor into the revealed lines does not end the follow
+
+        let moved = |cursor| {
+            MessageIde::Cursor(CursorsInfo {
+                id: None,
+                cursors: vec![Cursor {
+                    cursor,
+                    anchor: cursor,
+                }],
+                rev_num: None,
+            })
+        };
+        client.on_message_ide(moved(4)).await;
+
+        assert!(ide_receiver.try_recv().is_err());
+
+        // moving within them afterwards does
+
+        client.on_message_ide(moved(6)).await;
+
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::FollowEnded { id: 1 }));
+    }
+
+    #[tokio::test]
+    async fn viewport_lines() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Bytes);
 
         client
-            .on_message_ide(MessageIde::Update {
-                changes: vec![
-                    TextModification {
-                        offset: 0,
-                        delete: 1,
-                        text: "Ç".into(),
-                    },
-                    TextModification {
-                        offset: 6,
-                        delete: 1,
-                        text: "M".into(),
-                    },
-                ],
+            .on_message_server(MessageServer::File {
+                file: "çalùt\nmonde".into(),
+                version: 1,
+                sealed: None,
             })
             .await;
+        let _ = ide_receiver.try_recv();
 
-        let mut server_modif = OperationSeq::default();
-        server_modif.insert("Ç");
-        server_modif.delete(1);
-        server_modif.retain(5);
-        server_modif.insert("M");
-        server_modif.delete(1);
-        server_modif.retain(4);
+        client
+            .on_message_ide(MessageIde::Viewport(ViewportInfo {
+                id: None,
+                viewports: vec![Viewport { start: 1, end: 2 }],
+                rev_num: None,
+            }))
+            .await;
 
         assert_eq!(
             server_receiver.try_recv(),
-            Ok(MessageServer::ServerUpdate(ModifRequest {
-                delta: server_modif,
-                rev_num: 4
+            Ok(MessageServer::Viewport(ViewportInfo {
+                id: None,
+                viewports: vec![Viewport { start: 6, end: 11 }],
+                rev_num: Some(1),
             }))
         );
 
-        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::Ack));
-
-        // server ack
+        client
+            .on_message_server(MessageServer::Viewport(ViewportInfo {
+                id: Some(2),
+                viewports: vec![Viewport { start: 0, end: 5 }],
+                rev_num: Some(1),
+            }))
+            .await;
 
-        client.on_message_server(MessageServer::Ack).await;
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Viewport(ViewportInfo {
+                id: Some(2),
+                viewports: vec![Viewport { start: 0, end: 1 }],
+                rev_num: None,
+            }))
+        );
     }
 
     #[tokio::test]
@@ -246,6 +1393,7 @@ mod test {
             .on_message_server(MessageServer::File {
                 file: "çalùt monde".into(),
                 version: 4,
+                sealed: None,
             })
             .await;
 
@@ -287,7 +1435,8 @@ mod test {
             server_receiver.try_recv(),
             Ok(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
-                rev_num: 4
+                rev_num: 4,
+                sealed: None,
             }))
         );
 
@@ -300,7 +1449,7 @@ mod test {
 
     #[tokio::test]
     async fn server_change_chars() {
-        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
         let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
         let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars);
 
@@ -310,6 +1459,7 @@ mod test {
             .on_message_server(MessageServer::File {
                 file: "çalùt monde".into(),
                 version: 4,
+                sealed: None,
             })
             .await;
 
@@ -333,6 +1483,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 5,
+                sealed: None,
             }))
             .await;
 
@@ -361,7 +1512,7 @@ mod test {
 
     #[tokio::test]
     async fn server_change_bytes() {
-        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (server_sender, _server_receiver) = tokio::sync::mpsc::channel(8);
         let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
         let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Bytes);
 
@@ -371,6 +1522,7 @@ mod test {
             .on_message_server(MessageServer::File {
                 file: "çalùt monde".into(),
                 version: 4,
+                sealed: None,
             })
             .await;
 
@@ -394,6 +1546,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 5,
+                sealed: None,
             }))
             .await;
 
@@ -432,6 +1585,7 @@ mod test {
             .on_message_server(MessageServer::File {
                 file: "Hello world".into(),
                 version: 4,
+                sealed: None,
             })
             .await;
 
@@ -452,6 +1606,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 5,
+                sealed: None,
             }))
             .await;
 
@@ -487,7 +1642,8 @@ mod test {
             server_receiver.try_recv(),
             Ok(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
-                rev_num: 5
+                rev_num: 5,
+                sealed: None,
             }))
         );
 
@@ -523,6 +1679,7 @@ mod test {
             .on_message_server(MessageServer::File {
                 file: "Hello world".into(),
                 version: 42,
+                sealed: None,
             })
             .await;
 
@@ -554,7 +1711,8 @@ mod test {
             server_receiver.try_recv(),
             Ok(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
-                rev_num: 42
+                rev_num: 42,
+                sealed: None,
             }))
         );
 
@@ -570,6 +1728,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 43,
+                sealed: None,
             }))
             .await;
 
@@ -603,6 +1762,7 @@ mod test {
             .on_message_server(MessageServer::File {
                 file: "Hello world".into(),
                 version: 42,
+                sealed: None,
             })
             .await;
 
@@ -634,7 +1794,8 @@ mod test {
             server_receiver.try_recv(),
             Ok(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
-                rev_num: 42
+                rev_num: 42,
+                sealed: None,
             }))
         );
 
@@ -650,6 +1811,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 43,
+                sealed: None,
             }))
             .await;
 
@@ -706,6 +1868,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 44,
+                sealed: None,
             }))
             .await;
 
@@ -753,7 +1916,8 @@ mod test {
             server_receiver.try_recv(),
             Ok(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
-                rev_num: 45
+                rev_num: 45,
+                sealed: None,
             }))
         );
 
@@ -767,6 +1931,7 @@ mod test {
             .on_message_server(MessageServer::ServerUpdate(ModifRequest {
                 delta: server_modif,
                 rev_num: 46,
+                sealed: None,
             }))
             .await;
 
@@ -789,4 +1954,182 @@ mod test {
 
         client.on_message_server(MessageServer::Ack).await;
     }
+
+    #[tokio::test]
+    async fn end_to_end_change() {
+        let key = SessionKey::derive(&generate_secret()).unwrap();
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars)
+            .with_session_key(key.clone());
+
+        let (other_server_sender, _other_server_receiver) = tokio::sync::mpsc::channel(8);
+        let (other_ide_sender, mut other_ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut other = Client::new(
+            Server::new(other_server_sender),
+            Ide::new(other_ide_sender),
+            1,
+            Format::Chars,
+        )
+        .with_session_key(key);
+
+        // the file never reaches the server in clear
+
+        client.on_message_server(MessageServer::RequestFile).await;
+        assert_eq!(ide_receiver.try_recv(), Ok(MessageIde::RequestFile));
+
+        client
+            .on_message_ide(MessageIde::File {
+                file: "Hello world".into(),
+            })
+            .await;
+
+        let Ok(file @ MessageServer::File { .. }) = server_receiver.try_recv() else {
+            panic!("client should send the file");
+        };
+        let MessageServer::File { file: masked, .. } = &file else {
+            unreachable!()
+        };
+        assert!(masked.chars().all(|c| c == MASK));
+
+        other.on_message_server(file).await;
+        assert_eq!(
+            other_ide_receiver.try_recv(),
+            Ok(MessageIde::File {
+                file: "Hello world".into()
+            })
+        );
+
+        // neither does the inserted text
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 5,
+                    delete: 0,
+                    text: " new".into(),
+                }],
+            })
+            .await;
+
+        let Ok(MessageServer::ServerUpdate(mut modif)) = server_receiver.try_recv() else {
+            panic!("client should send its change");
+        };
+        assert!(modif.sealed.is_some());
+        assert!(!format!("{:?}", modif.delta).contains("new"));
+
+        modif.rev_num = 1;
+        other
+            .on_message_server(MessageServer::ServerUpdate(modif))
+            .await;
+        assert_eq!(
+            other_ide_receiver.try_recv(),
+            Ok(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 5,
+                    delete: 0,
+                    text: " new".into(),
+                }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn end_to_end_chat() {
+        let key = SessionKey::derive(&generate_secret()).unwrap();
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, mut ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Bytes)
+            .with_session_key(key.clone());
+
+        let (file, sealed) = key.seal_text("çalùt monde");
+        client
+            .on_message_server(MessageServer::File {
+                file,
+                version: 0,
+                sealed: Some(sealed),
+            })
+            .await;
+        let _ = ide_receiver.try_recv();
+
+        client
+            .on_message_ide(MessageIde::Chat(ChatMessage {
+                text: "Typo here".into(),
+                anchor: Some(8),
+                ..Default::default()
+            }))
+            .await;
+
+        let Ok(MessageServer::Chat(mut message)) = server_receiver.try_recv() else {
+            panic!("client should send the chat message");
+        };
+        assert_eq!(message.text, "");
+        // the anchor stays in the clear for the server to move it along with the edits
+        assert_eq!(message.anchor, Some(6));
+
+        message.author = Some(0);
+        message.name = Some("Alice".into());
+        message.timestamp = Some(42);
+        client.on_message_server(MessageServer::Chat(message)).await;
+
+        assert_eq!(
+            ide_receiver.try_recv(),
+            Ok(MessageIde::Chat(ChatMessage {
+                author: Some(0),
+                name: Some("Alice".into()),
+                timestamp: Some(42),
+                text: "Typo here".into(),
+                anchor: Some(8),
+                rev_num: None,
+                sealed: None,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn activity_states() {
+        let (server_sender, mut server_receiver) = tokio::sync::mpsc::channel(8);
+        let (ide_sender, _ide_receiver) = tokio::sync::mpsc::channel(8);
+        let mut client = Client::new(Server::new(server_sender), Ide::new(ide_sender), 0, Format::Chars)
+            .with_idle_after(Duration::from_secs(10));
+        let activity = |activity| Ok(MessageServer::Activity(ActivityInfo { id: None, activity }));
+
+        client
+            .on_message_server(MessageServer::File {
+                file: "Hello world".into(),
+                version: 0,
+                sealed: None,
+            })
+            .await;
+        let now = Instant::now();
+        client.on_tick(now).await;
+        assert_eq!(server_receiver.try_recv(), activity(Activity::Active));
+
+        client
+            .on_message_ide(MessageIde::Update {
+                changes: vec![TextModification {
+                    offset: 0,
+                    delete: 0,
+                    text: "a".into(),
+                }],
+            })
+            .await;
+        let _ = server_receiver.try_recv();
+        let now = Instant::now();
+        client.on_tick(now).await;
+        assert_eq!(server_receiver.try_recv(), activity(Activity::Typing));
+
+        // unchanged states are not published again
+        client.on_tick(now).await;
+        assert!(server_receiver.try_recv().is_err());
+
+        client.on_tick(now + Duration::from_secs(3)).await;
+        assert_eq!(server_receiver.try_recv(), activity(Activity::Active));
+
+        client.on_tick(now + Duration::from_secs(10)).await;
+        assert_eq!(server_receiver.try_recv(), activity(Activity::Idle));
+
+        client.on_message_ide(MessageIde::Detach).await;
+        assert_eq!(server_receiver.try_recv(), activity(Activity::Away));
+    }
 }
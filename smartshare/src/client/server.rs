@@ -11,6 +11,13 @@ impl Server {
         Self { sender }
     }
 
+    /// Stands in for the server while the connection is lost, dropping what is sent.
+    pub fn offline() -> Self {
+        let (sender, mut receiver) = mpsc::channel(8);
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        Self { sender }
+    }
+
     pub async fn send(&self, message: MessageServer) -> anyhow::Result<()> {
         self.sender.send(message).await.map_err(Into::into)
     }
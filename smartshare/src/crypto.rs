@@ -0,0 +1,203 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use anyhow::{anyhow, ensure};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use operational_transform::{Operation, OperationSeq};
+use sha2::Sha256;
+
+/// Character standing in for every inserted character the server is not allowed to read.
+/// Only lengths matter to the transformations done by the server, so masked deltas can still be
+/// transformed and applied there.
+pub const MASK: char = '\u{FFFD}';
+
+const NONCE_LEN: usize = 12;
+const SECRET_LEN: usize = 32;
+
+/// Random bytes encoded in base64, for values that must not be guessed or reused.
+pub fn random(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    STANDARD.encode(bytes)
+}
+
+/// A new session secret. Only generated secrets are accepted: the server holds every sealed
+/// payload, so a secret chosen by a human could be guessed offline.
+pub fn generate_secret() -> String {
+    random(SECRET_LEN)
+}
+
+#[derive(Clone)]
+pub struct SessionKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SessionKey {
+    pub fn derive(secret: &str) -> anyhow::Result<Self> {
+        let secret = STANDARD
+            .decode(secret)
+            .ok()
+            .filter(|secret| secret.len() == SECRET_LEN)
+            .ok_or_else(|| {
+                anyhow!("The session secret should be made with client --generate-secret")
+            })?;
+        let hkdf = Hkdf::<Sha256>::new(Some(b"smartshare"), &secret);
+        let mut key = [0u8; 32];
+        hkdf.expand(b"smartshare end-to-end v1", &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(&nonce, plaintext)
+                .expect("encryption should not fail"),
+        );
+        STANDARD.encode(sealed)
+    }
+
+    pub fn open(&self, sealed: &str) -> anyhow::Result<Vec<u8>> {
+        let sealed = STANDARD.decode(sealed)?;
+        ensure!(sealed.len() >= NONCE_LEN, "sealed payload is too short");
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("could not decrypt payload, is the session secret right ?"))
+    }
+
+    pub fn seal_text(&self, text: &str) -> (String, String) {
+        let masked = text.chars().map(|_| MASK).collect();
+        (masked, self.seal(text.as_bytes()))
+    }
+
+    pub fn open_text(&self, masked: &str, sealed: &str) -> anyhow::Result<String> {
+        let text = String::from_utf8(self.open(sealed)?)?;
+        ensure!(
+            text.chars().count() == masked.chars().count(),
+            "sealed text does not match its masked length"
+        );
+        Ok(text)
+    }
+
+    /// Replaces every inserted character of `delta` by [`MASK`] and seals the inserted text, in
+    /// order. Transforming the masked delta keeps its inserts in the same order, which is all
+    /// [`SessionKey::open_delta`] needs to put the text back.
+    pub fn seal_delta(&self, delta: &OperationSeq) -> (OperationSeq, String) {
+        let mut masked = OperationSeq::default();
+        let mut inserted = String::new();
+        for op in delta.ops() {
+            match op {
+                Operation::Retain(n) => masked.retain(*n),
+                Operation::Delete(n) => masked.delete(*n),
+                Operation::Insert(text) => {
+                    masked.insert(&text.chars().map(|_| MASK).collect::<String>());
+                    inserted.push_str(text);
+                }
+            }
+        }
+        (masked, self.seal(inserted.as_bytes()))
+    }
+
+    pub fn open_delta(&self, masked: &OperationSeq, sealed: &str) -> anyhow::Result<OperationSeq> {
+        let inserted = String::from_utf8(self.open(sealed)?)?;
+        let mut chars = inserted.chars();
+        let mut delta = OperationSeq::default();
+        for op in masked.ops() {
+            match op {
+                Operation::Retain(n) => delta.retain(*n),
+                Operation::Delete(n) => delta.delete(*n),
+                Operation::Insert(text) => {
+                    let len = text.chars().count();
+                    let text = chars.by_ref().take(len).collect::<String>();
+                    ensure!(
+                        text.chars().count() == len,
+                        "sealed delta is shorter than its inserts"
+                    );
+                    delta.insert(&text);
+                }
+            }
+        }
+        ensure!(
+            chars.next().is_none(),
+            "sealed delta is longer than its inserts"
+        );
+        Ok(delta)
+    }
+}
+
+impl FromStr for SessionKey {
+    type Err = anyhow::Error;
+
+    fn from_str(secret: &str) -> Result<Self, Self::Err> {
+        Self::derive(secret)
+    }
+}
+
+impl Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn refuse_chosen_secret() {
+        assert!(SessionKey::derive("correct horse battery staple").is_err());
+        assert!(SessionKey::derive(&random(16)).is_err());
+        assert!(SessionKey::derive(&generate_secret()).is_ok());
+    }
+
+    #[test]
+    fn open_sealed_text() {
+        let key = SessionKey::derive(&generate_secret()).unwrap();
+        let (masked, sealed) = key.seal_text("çalùt monde");
+
+        assert_eq!(masked.chars().count(), 11);
+        assert!(masked.chars().all(|c| c == MASK));
+        assert_eq!(key.open_text(&masked, &sealed).unwrap(), "çalùt monde");
+    }
+
+    #[test]
+    fn open_with_wrong_secret() {
+        let sealed = SessionKey::derive(&generate_secret())
+            .unwrap()
+            .seal(b"Hello world");
+
+        let other = SessionKey::derive(&generate_secret()).unwrap();
+        assert!(other.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn open_transformed_delta() {
+        let key = SessionKey::derive(&generate_secret()).unwrap();
+
+        let mut delta = OperationSeq::default();
+        delta.insert("Hello");
+        delta.retain(2);
+        delta.insert(" world");
+        let (masked, sealed) = key.seal_delta(&delta);
+
+        let mut concurrent = OperationSeq::default();
+        concurrent.retain(1);
+        concurrent.delete(1);
+        concurrent.insert("!");
+
+        let (_, masked_p) = concurrent.transform(&masked).unwrap();
+        let (_, delta_p) = concurrent.transform(&delta).unwrap();
+
+        assert_eq!(key.open_delta(&masked_p, &sealed).unwrap(), delta_p);
+    }
+}
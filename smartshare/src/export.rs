@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure};
+
+use crate::crypto::SessionKey;
+use crate::file::File;
+use crate::protocol::msg::MessageServer;
+use crate::server::recording::RecordedEvent;
+
+/// Consecutive revisions of a recording turned into a single commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub author: String,
+    pub co_authors: Vec<String>,
+    pub timestamp: u64,
+    pub first_rev: usize,
+    pub last_rev: usize,
+    pub text: String,
+}
+
+#[derive(Default)]
+struct Group {
+    revisions: Vec<usize>,
+    start: u64,
+    end: u64,
+    first_rev: usize,
+    last_rev: usize,
+}
+
+impl Group {
+    fn snapshot(&self, names: &HashMap<usize, String>, text: String) -> Snapshot {
+        let name = |id: usize| {
+            names
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| format!("Guest {id}"))
+        };
+        // Whoever made most of the revisions authors the commit, ties going to the first of them
+        let mut counts: Vec<(usize, usize)> = vec![];
+        for &author in &self.revisions {
+            match counts.iter_mut().find(|(id, _)| *id == author) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((author, 1)),
+            }
+        }
+        let author = counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .map(|(id, _)| *id)
+            .expect("a group has at least one revision");
+        Snapshot {
+            author: name(author),
+            co_authors: counts
+                .iter()
+                .filter(|(id, _)| *id != author)
+                .map(|(id, _)| name(*id))
+                .collect(),
+            timestamp: self.end,
+            first_rev: self.first_rev,
+            last_rev: self.last_rev,
+            text,
+        }
+    }
+}
+
+/// Replays a recording and groups its revisions into windows of at most `window`, each window
+/// giving the text after its last revision.
+pub fn snapshots(
+    events: &[RecordedEvent],
+    window: Duration,
+    key: Option<&SessionKey>,
+) -> anyhow::Result<Vec<Snapshot>> {
+    let window = window.as_millis() as u64;
+    let mut names = HashMap::new();
+    let mut file: Option<File> = None;
+    let mut group: Option<Group> = None;
+    let mut snapshots = vec![];
+    for event in events {
+        match &event.message {
+            MessageServer::Joined(participant) => {
+                names.insert(participant.id, participant.name.clone());
+            }
+            MessageServer::File {
+                file: text, sealed, ..
+            } => {
+                ensure!(file.is_none(), "The recording holds more than one file");
+                let text = match (sealed, key) {
+                    (Some(sealed), Some(key)) => key.open_text(text, sealed)?,
+                    (Some(_), None) => bail!("The recording is end-to-end encrypted"),
+                    (None, _) => text.clone(),
+                };
+                file = Some(File::new(&text));
+            }
+            MessageServer::ServerUpdate(req) => {
+                let file = file
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("Revision {} recorded before the file", req.rev_num))?;
+                if let Some(previous) = group
+                    .take_if(|previous| event.timestamp.saturating_sub(previous.start) > window)
+                {
+                    snapshots.push(previous.snapshot(&names, file.to_string()));
+                }
+                match (&req.sealed, key) {
+                    (Some(sealed), Some(key)) => {
+                        file.apply(&key.open_delta(&req.delta, sealed)?)?
+                    }
+                    (Some(_), None) => bail!("The recording is end-to-end encrypted"),
+                    (None, _) => file.apply(&req.delta)?,
+                }
+
+                let current = group.get_or_insert_with(|| Group {
+                    start: event.timestamp,
+                    first_rev: req.rev_num,
+                    ..Default::default()
+                });
+                current.revisions.push(event.author);
+                current.end = event.timestamp;
+                current.last_rev = req.rev_num;
+            }
+            _ => (),
+        }
+    }
+    if let (Some(current), Some(file)) = (group, &file) {
+        snapshots.push(current.snapshot(&names, file.to_string()));
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod test {
+    use operational_transform::OperationSeq;
+
+    use crate::protocol::msg::{ModifRequest, Participant};
+
+    use super::*;
+
+    fn event(timestamp: u64, author: usize, message: MessageServer) -> RecordedEvent {
+        RecordedEvent {
+            timestamp,
+            author,
+            message,
+        }
+    }
+
+    fn append(timestamp: u64, author: usize, rev_num: usize, text: &str) -> RecordedEvent {
+        let mut delta = OperationSeq::default();
+        delta.retain(rev_num as u64 - 1);
+        delta.insert(text);
+        event(
+            timestamp,
+            author,
+            MessageServer::ServerUpdate(ModifRequest {
+                delta,
+                rev_num,
+                sealed: None,
+            }),
+        )
+    }
+
+    #[test]
+    fn group_revisions_by_window() {
+        let joined = |id: usize, name: &str| {
+            event(
+                0,
+                id,
+                MessageServer::Joined(Participant {
+                    id,
+                    name: name.into(),
+                    color: "#FA8072".into(),
+                }),
+            )
+        };
+        let events = vec![
+            joined(0, "Alice"),
+            event(
+                0,
+                0,
+                MessageServer::File {
+                    file: "".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            ),
+            joined(1, "Bob"),
+            append(1_000, 0, 1, "a"),
+            append(2_000, 1, 2, "b"),
+            append(3_000, 0, 3, "c"),
+            append(20_000, 1, 4, "d"),
+        ];
+
+        let snapshots = snapshots(&events, Duration::from_secs(10), None).unwrap();
+
+        assert_eq!(
+            snapshots,
+            vec![
+                Snapshot {
+                    author: "Alice".into(),
+                    co_authors: vec!["Bob".into()],
+                    timestamp: 3_000,
+                    first_rev: 1,
+                    last_rev: 3,
+                    text: "abc".into(),
+                },
+                Snapshot {
+                    author: "Bob".into(),
+                    co_authors: vec![],
+                    timestamp: 20_000,
+                    first_rev: 4,
+                    last_rev: 4,
+                    text: "abcd".into(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context};
+use clap::Parser;
+use smartshare::crypto::SessionKey;
+use smartshare::export::{snapshots, Snapshot};
+use smartshare::server::recording;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// recording of the session, made by the server with --record
+    recording: PathBuf,
+
+    /// path of the shared file inside the repository
+    #[arg(long)]
+    path: String,
+
+    /// branch created with one commit per window of edits
+    #[arg(long)]
+    branch: String,
+
+    /// commit the branch starts from
+    #[arg(long, default_value = "HEAD")]
+    base: String,
+
+    /// repository the commits are written to
+    #[arg(long, default_value = ".")]
+    repo: PathBuf,
+
+    /// seconds of edits grouped into a single commit
+    #[arg(long, default_value_t = 300)]
+    window: u64,
+
+    /// domain of the emails given to the participants
+    #[arg(long, default_value = "smartshare.invalid")]
+    email_domain: String,
+
+    /// secret of the session when it was encrypted end-to-end
+    #[arg(long)]
+    secret: Option<SessionKey>,
+}
+
+/// Runs git plumbing commands against a private index, leaving the working tree untouched.
+struct Git {
+    repo: PathBuf,
+    index: PathBuf,
+}
+
+impl Git {
+    fn run(
+        &self,
+        args: &[&str],
+        env: &[(&str, String)],
+        input: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(&self.repo)
+            .args(args)
+            .env("GIT_INDEX_FILE", &self.index)
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Could not run git")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.unwrap_or_default().as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        ensure!(
+            output.status.success(),
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+}
+
+fn email(name: &str, domain: &str) -> String {
+    let local: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("{}@{domain}", local.trim_matches('-'))
+}
+
+fn message(snapshot: &Snapshot, path: &str, domain: &str) -> String {
+    let mut message = if snapshot.first_rev == snapshot.last_rev {
+        format!("Edit {path} (revision {})\n", snapshot.first_rev)
+    } else {
+        format!(
+            "Edit {path} (revisions {} to {})\n",
+            snapshot.first_rev, snapshot.last_rev
+        )
+    };
+    if !snapshot.co_authors.is_empty() {
+        message.push('\n');
+    }
+    for co_author in &snapshot.co_authors {
+        message.push_str(&format!(
+            "Co-authored-by: {co_author} <{}>\n",
+            email(co_author, domain)
+        ));
+    }
+    message
+}
+
+fn export(args: &Args, snapshots: &[Snapshot], git: &Git) -> anyhow::Result<()> {
+    let branch = format!("refs/heads/{}", args.branch);
+    if git
+        .run(&["rev-parse", "--verify", "--quiet", &branch], &[], None)
+        .is_ok()
+    {
+        bail!("Branch {} already exists", args.branch);
+    }
+    let mut parent = git.run(
+        &[
+            "rev-parse",
+            "--verify",
+            &format!("{}^{{commit}}", args.base),
+        ],
+        &[],
+        None,
+    )?;
+    git.run(&["read-tree", &parent], &[], None)?;
+
+    for snapshot in snapshots {
+        let blob = git.run(&["hash-object", "-w", "--stdin"], &[], Some(&snapshot.text))?;
+        git.run(
+            &[
+                "update-index",
+                "--add",
+                "--cacheinfo",
+                &format!("100644,{blob},{}", args.path),
+            ],
+            &[],
+            None,
+        )?;
+        let tree = git.run(&["write-tree"], &[], None)?;
+        let env = [
+            ("GIT_AUTHOR_NAME", snapshot.author.clone()),
+            (
+                "GIT_AUTHOR_EMAIL",
+                email(&snapshot.author, &args.email_domain),
+            ),
+            (
+                "GIT_AUTHOR_DATE",
+                format!("@{} +0000", snapshot.timestamp / 1000),
+            ),
+        ];
+        parent = git.run(
+            &["commit-tree", &tree, "-p", &parent],
+            &env,
+            Some(&message(snapshot, &args.path, &args.email_domain)),
+        )?;
+    }
+
+    // An empty old value makes sure the branch was not created in the meantime
+    git.run(&["update-ref", &branch, &parent, ""], &[], None)?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let events = recording::load(&args.recording).await?;
+    let snapshots = snapshots(
+        &events,
+        Duration::from_secs(args.window),
+        args.secret.as_ref(),
+    )?;
+    ensure!(!snapshots.is_empty(), "The recording holds no revision");
+
+    let git = Git {
+        repo: args.repo.clone(),
+        index: std::env::temp_dir().join(format!("smartshare-export-{}.index", std::process::id())),
+    };
+    let res = export(&args, &snapshots, &git);
+    let _ = std::fs::remove_file(&git.index);
+    res?;
+
+    println!("Exported {} commits to {}", snapshots.len(), args.branch);
+    Ok(())
+}
@@ -3,7 +3,6 @@ use std::fmt::Display;
 use anyhow::{anyhow, ensure};
 use operational_transform::{Operation, OperationSeq};
 use ropey::Rope;
-use tracing::Instrument;
 
 use crate::protocol::msg::{CursorsInfo, TextModification};
 
@@ -53,6 +52,18 @@ impl File {
         self.content.len_bytes()
     }
 
+    pub fn len_lines(&self) -> usize {
+        self.content.len_lines()
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.content.line_to_char(line)
+    }
+
+    pub fn char_to_line(&self, offset: usize) -> usize {
+        self.content.char_to_line(offset)
+    }
+
     pub fn byte_to_char_modif(&self, modif: &mut TextModification) {
         modif.delete = self
             .content
@@ -70,6 +81,22 @@ impl File {
         modif.offset = self.content.slice(..modif.offset as usize).len_bytes() as u64;
     }
 
+    pub fn byte_to_char_offset(&self, offset: u64) -> anyhow::Result<u64> {
+        Ok(self
+            .content
+            .get_byte_slice(..offset as usize)
+            .ok_or_else(|| anyhow!("invalid offset"))?
+            .len_chars() as u64)
+    }
+
+    pub fn char_to_byte_offset(&self, offset: u64) -> anyhow::Result<u64> {
+        Ok(self
+            .content
+            .get_slice(..offset as usize)
+            .ok_or_else(|| anyhow!("invalid offset"))?
+            .len_bytes() as u64)
+    }
+
     pub fn byte_to_char_cursor(&self, cursor_info: &mut CursorsInfo) -> anyhow::Result<()> {
         for cursor in cursor_info.cursors.iter_mut() {
             cursor.cursor = self
@@ -0,0 +1,229 @@
+use std::fmt::Display;
+use std::net::UdpSocket;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure};
+
+pub const SCHEME: &str = "smartshare://";
+pub const DEFAULT_PORT: u16 = 4903;
+
+/// Everything a participant needs to join a session, shareable as a single
+/// `smartshare://host:port/room?token=...&fingerprint=...` string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub host: String,
+    pub port: u16,
+    pub room: Option<String>,
+    pub token: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+impl Invite {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            room: None,
+            token: None,
+            fingerprint: None,
+        }
+    }
+
+    /// The same invite with its token hidden, safe to write to the logs.
+    pub fn redacted(&self) -> Self {
+        Self {
+            token: self.token.as_ref().map(|_| "redacted".into()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Guesses the address under which this machine is reachable by the other participants.
+pub fn local_host() -> Option<String> {
+    // Connecting an udp socket sends nothing but tells which interface would be used
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+fn encode(component: &str) -> String {
+    let mut encoded = String::new();
+    for byte in component.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn decode(component: &str) -> anyhow::Result<String> {
+    let mut decoded = Vec::new();
+    let mut bytes = component.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes.next().ok_or_else(|| anyhow!("truncated escape"))?,
+                bytes.next().ok_or_else(|| anyhow!("truncated escape"))?,
+            ];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+fn split_host_port(authority: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("unterminated ipv6 address"))?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => Some(port),
+                None if rest.is_empty() => None,
+                None => bail!("invalid address {authority}"),
+            };
+            (host, port)
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    ensure!(!host.is_empty(), "missing host in {authority}");
+    let port = match port {
+        Some(port) => port.parse()?,
+        None => DEFAULT_PORT,
+    };
+    Ok((host.to_owned(), port))
+}
+
+impl FromStr for Invite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix(SCHEME) else {
+            // A bare address is an invite without room nor credentials
+            let (host, port) = split_host_port(s)?;
+            return Ok(Invite::new(host, port));
+        };
+
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        let (authority, room) = match rest.split_once('/') {
+            Some((authority, room)) => (authority, Some(room)),
+            None => (rest, None),
+        };
+
+        let (host, port) = split_host_port(authority)?;
+        let mut invite = Invite::new(host, port);
+        invite.room = room
+            .filter(|room| !room.is_empty())
+            .map(decode)
+            .transpose()?;
+
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = decode(value)?;
+            match key {
+                "token" => invite.token = Some(value),
+                "fingerprint" => invite.fingerprint = Some(value),
+                _ => bail!("unknown invite parameter {key}"),
+            }
+        }
+
+        Ok(invite)
+    }
+}
+
+impl Display for Invite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(SCHEME)?;
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)?;
+        } else {
+            write!(f, "{}:{}", self.host, self.port)?;
+        }
+        if let Some(room) = &self.room {
+            write!(f, "/{}", encode(room))?;
+        }
+        let params = [("token", &self.token), ("fingerprint", &self.fingerprint)];
+        let mut separator = '?';
+        for (key, value) in params {
+            if let Some(value) = value {
+                write!(f, "{separator}{key}={}", encode(value))?;
+                separator = '&';
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_bare_address() {
+        let invite: Invite = "192.168.1.12".parse().unwrap();
+
+        assert_eq!(invite, Invite::new("192.168.1.12", DEFAULT_PORT));
+
+        let invite: Invite = "[::1]:5000".parse().unwrap();
+
+        assert_eq!(invite, Invite::new("::1", 5000));
+    }
+
+    #[test]
+    fn parse_full_invite() {
+        let invite: Invite =
+            "smartshare://example.org:5000/mob%20session?token=s3cr%26t&fingerprint=AB:CD"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            invite,
+            Invite {
+                host: "example.org".into(),
+                port: 5000,
+                room: Some("mob session".into()),
+                token: Some("s3cr&t".into()),
+                fingerprint: Some("AB:CD".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let invite = Invite {
+            host: "::1".into(),
+            port: DEFAULT_PORT,
+            room: Some("mob session".into()),
+            token: Some("s3cr&t".into()),
+            fingerprint: None,
+        };
+
+        assert_eq!(
+            invite.to_string(),
+            "smartshare://[::1]:4903/mob%20session?token=s3cr%26t"
+        );
+        assert_eq!(invite.to_string().parse::<Invite>().unwrap(), invite);
+    }
+
+    #[test]
+    fn redact_token() {
+        let mut invite = Invite::new("example.org", 5000);
+        invite.token = Some("s3cret".into());
+
+        assert_eq!(
+            invite.redacted().to_string(),
+            "smartshare://example.org:5000?token=redacted"
+        );
+    }
+}
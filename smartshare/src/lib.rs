@@ -1,2 +1,6 @@
 pub mod protocol;
 pub mod file;
+pub mod crypto;
+pub mod invite;
+pub mod export;
+pub mod server;
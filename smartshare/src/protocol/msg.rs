@@ -1,5 +1,3 @@
-use std::usize;
-
 use clap::ValueEnum;
 use operational_transform::{Operation, OperationSeq};
 use serde::{Deserialize, Serialize};
@@ -11,8 +9,42 @@ pub enum MessageServer {
     Ack,
     Error { error: String },
     RequestFile,
-    File { file: String, version: usize },
+    File {
+        file: String,
+        version: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sealed: Option<String>,
+    },
     Cursor(CursorsInfo),
+    RequestAudit(AuditQuery),
+    Audit { entries: Vec<AuditEntry> },
+    Join(JoinRequest),
+    Welcome { id: usize, session: String },
+    Roster { participants: Vec<Participant> },
+    Joined(Participant),
+    Left { id: usize },
+    Viewport(ViewportInfo),
+    Chat(ChatMessage),
+    CreateThread(NewThread),
+    Reply { thread: usize, comment: Comment },
+    Resolve { thread: usize },
+    ListThreads,
+    Threads { threads: Vec<Thread> },
+    ThreadUpdated(Thread),
+    Activity(ActivityInfo),
+    Present { enabled: bool },
+    PresenterChanged { id: Option<usize> },
+    RequestRevision { rev_num: usize },
+    Revision { rev_num: usize, file: String },
+    RequestBlame,
+    Blame { ranges: Vec<BlameRange> },
+    RequestDiff { from: usize, to: usize },
+    Diff(RevisionDiff),
+    Checkpoint { name: String },
+    Checkpoints { checkpoints: Vec<Checkpoint> },
+    Revert { rev_num: usize },
+    RevertToCheckpoint { name: String },
+    Resumed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -24,12 +56,57 @@ pub enum MessageIde {
     File { file: String },
     Ack,
     Cursor(CursorsInfo),
+    RequestAudit(AuditQuery),
+    Audit { entries: Vec<AuditEntry> },
+    Welcome { id: usize },
+    Invite { invite: String },
+    Roster { participants: Vec<Participant> },
+    Joined(Participant),
+    Left { id: usize },
+    ClearCursors { id: usize },
+    Follow { id: Option<usize> },
+    ScrollTo { id: usize, offset: u64 },
+    FollowEnded { id: usize },
+    Viewport(ViewportInfo),
+    Reveal { id: usize, viewport: Viewport },
+    Chat(ChatMessage),
+    CreateThread(NewThread),
+    Reply { thread: usize, comment: Comment },
+    Resolve { thread: usize },
+    ListThreads,
+    Threads { threads: Vec<Thread> },
+    ThreadUpdated(Thread),
+    Activity(ActivityInfo),
+    Detach,
+    Attach,
+    Present { enabled: bool },
+    PresenterChanged { id: Option<usize> },
+    WatchPresenter { enabled: bool },
+    Undo,
+    Redo,
+    JournalFound { rev_num: usize },
+    ReplayJournal { replay: bool },
+    Fork,
+    Merge,
+    DiscardFork,
+    RequestRevision { rev_num: usize },
+    Revision { rev_num: usize, file: String },
+    RequestBlame,
+    Blame { ranges: Vec<BlameRange> },
+    RequestDiff { from: usize, to: usize },
+    Diff(RevisionDiff),
+    Checkpoint { name: String },
+    Checkpoints { checkpoints: Vec<Checkpoint> },
+    Revert { rev_num: usize },
+    RevertToCheckpoint { name: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct CursorsInfo {
     pub id: Option<usize>,
     pub cursors: Vec<Cursor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev_num: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -38,6 +115,179 @@ pub struct Cursor {
     pub anchor: u64
 }
 
+/// Ranges of the document visible in a participant's editor.
+///
+/// The ide gives and gets ranges of lines, the end excluded. Between the clients and the server
+/// they are char offsets instead, so that they can be moved along with the edits like cursors.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ViewportInfo {
+    pub id: Option<usize>,
+    pub viewports: Vec<Viewport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev_num: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Viewport {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// A chat message, the author, name and timestamp are filled by the server.
+///
+/// In an end-to-end encrypted session only the text is sealed, the server moving the anchor along
+/// with the edits like cursors and thread ranges.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ChatMessage {
+    #[serde(default)]
+    pub author: Option<usize>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev_num: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ActivityInfo {
+    pub id: Option<usize>,
+    pub activity: Activity,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Activity {
+    Typing,
+    Active,
+    Idle,
+    Away,
+}
+
+/// A comment thread attached to a range of the document.
+///
+/// In an end-to-end encrypted session only the comments are sealed: the server moves the range
+/// along with the edits, whose positions it sees anyway.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Thread {
+    pub id: usize,
+    pub start: u64,
+    pub end: u64,
+    pub comments: Vec<Comment>,
+    pub resolved: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev_num: Option<usize>,
+}
+
+/// The range applies to revision `rev_num` followed by the author's own later revisions, so a
+/// client only sends it once every edit it takes into account was sent.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NewThread {
+    pub start: u64,
+    pub end: u64,
+    pub comment: Comment,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev_num: Option<usize>,
+}
+
+/// A comment of a thread, the author, name and timestamp are filled by the server.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct Comment {
+    #[serde(default)]
+    pub author: Option<usize>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct JoinRequest {
+    #[serde(default)]
+    pub room: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<Resume>,
+}
+
+/// Where a client that lost its connection left the session, as revision `rev_num` under `id`.
+///
+/// `session` is the nonce the server welcomed the client with, ids and revisions being
+/// meaningless to another server or after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Resume {
+    pub rev_num: usize,
+    pub id: usize,
+    pub session: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Participant {
+    pub id: usize,
+    pub name: String,
+    pub color: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub from: Option<usize>,
+    #[serde(default)]
+    pub to: Option<usize>,
+    #[serde(default)]
+    pub author: Option<usize>,
+}
+
+/// A revision tagged with a name.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Checkpoint {
+    pub name: String,
+    pub rev_num: usize,
+    pub author: usize,
+    pub timestamp: u64,
+}
+
+/// Changes from revision `from` to revision `to`, as a single delta in chars and as a unified diff.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RevisionDiff {
+    pub from: usize,
+    pub to: usize,
+    pub delta: OperationSeq,
+    pub unified: String,
+}
+
+/// Lines `start..end`, counted from 0, last changed by `author` at `rev_num`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlameRange {
+    pub start: usize,
+    pub end: usize,
+    pub author: usize,
+    pub rev_num: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    pub rev_num: usize,
+    pub author: usize,
+    pub identity: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub timestamp: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct TextModification {
     pub offset: u64,
@@ -49,13 +299,15 @@ pub struct TextModification {
 pub struct ModifRequest {
     pub delta: OperationSeq,
     pub rev_num: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
 }
 
 pub fn modifs_to_operation_seq(
-    modifs: &Vec<TextModification>,
+    modifs: &[TextModification],
     src_length: &u64,
 ) -> Result<OperationSeq, anyhow::Error> {
-    let mut op_seq = match modifs.get(0) {
+    let mut op_seq = match modifs.first() {
         Some(modif) => modif_to_operation_seq(modif, src_length)?,
         None => {
             let mut noop = OperationSeq::default();
@@ -89,6 +341,40 @@ pub fn modif_to_operation_seq(
     Ok(seq)
 }
 
+/// Moves a position of the document the delta applies to to the same place in the result.
+pub fn transform_index(delta: &OperationSeq, index: u64) -> u64 {
+    let mut new_index = index;
+    let mut position = 0;
+    for op in delta.ops() {
+        if position > index {
+            break;
+        }
+        match op {
+            Operation::Retain(retain) => position += retain,
+            Operation::Insert(insert) => new_index += insert.chars().count() as u64,
+            Operation::Delete(delete) => {
+                new_index -= (*delete).min(index - position);
+                position += delete;
+            }
+        }
+    }
+    new_index
+}
+
+pub fn transform_cursors(delta: &OperationSeq, cursors: &mut [Cursor]) {
+    for cursor in cursors {
+        cursor.cursor = transform_index(delta, cursor.cursor);
+        cursor.anchor = transform_index(delta, cursor.anchor);
+    }
+}
+
+pub fn transform_viewports(delta: &OperationSeq, viewports: &mut [Viewport]) {
+    for viewport in viewports {
+        viewport.start = transform_index(delta, viewport.start);
+        viewport.end = transform_index(delta, viewport.end);
+    }
+}
+
 #[derive(Debug)]
 enum State {
     Ret,
@@ -168,3 +454,25 @@ pub fn to_ide_changes(delta: &OperationSeq) -> Vec<TextModification> {
     }
     modifs
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transform_index_through_delta() {
+        let mut delta = OperationSeq::default();
+        delta.retain(2);
+        delta.insert("abc");
+        delta.retain(2);
+        delta.delete(3);
+        delta.retain(1);
+
+        assert_eq!(transform_index(&delta, 0), 0);
+        assert_eq!(transform_index(&delta, 2), 5);
+        assert_eq!(transform_index(&delta, 4), 7);
+        assert_eq!(transform_index(&delta, 5), 7);
+        assert_eq!(transform_index(&delta, 7), 7);
+        assert_eq!(transform_index(&delta, 8), 8);
+    }
+}
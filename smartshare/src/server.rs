@@ -0,0 +1,8 @@
+pub mod blame;
+pub mod client;
+pub mod connection;
+pub mod limiter;
+pub mod metrics;
+pub mod recording;
+#[allow(clippy::module_inception)]
+pub mod server;
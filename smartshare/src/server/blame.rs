@@ -0,0 +1,177 @@
+use operational_transform::{Operation, OperationSeq};
+
+use crate::file::File;
+use crate::protocol::msg::BlameRange;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Span {
+    len: u64,
+    author: usize,
+    rev_num: usize,
+}
+
+/// Who inserted each character of the file, as consecutive spans of the same revision.
+#[derive(Debug, Default)]
+pub struct Authorship {
+    spans: Vec<Span>,
+}
+
+impl Authorship {
+    pub fn new(len: u64, author: usize) -> Self {
+        let mut authorship = Self::default();
+        authorship.push(Span {
+            len,
+            author,
+            rev_num: 0,
+        });
+        authorship
+    }
+
+    fn push(&mut self, span: Span) {
+        if span.len == 0 {
+            return;
+        }
+        match self.spans.last_mut() {
+            Some(last) if (last.author, last.rev_num) == (span.author, span.rev_num) => {
+                last.len += span.len
+            }
+            _ => self.spans.push(span),
+        }
+    }
+
+    pub fn apply(&mut self, delta: &OperationSeq, author: usize, rev_num: usize) {
+        let mut old = std::mem::take(&mut self.spans).into_iter();
+        let mut current: Option<Span> = None;
+        for op in delta.ops() {
+            let (mut len, keep) = match op {
+                Operation::Retain(n) => (*n, true),
+                Operation::Delete(n) => (*n, false),
+                Operation::Insert(text) => {
+                    self.push(Span {
+                        len: text.chars().count() as u64,
+                        author,
+                        rev_num,
+                    });
+                    continue;
+                }
+            };
+            while len > 0 {
+                let Some(mut span) = current.take().or_else(|| old.next()) else {
+                    break;
+                };
+                let taken = span.len.min(len);
+                len -= taken;
+                if keep {
+                    self.push(Span { len: taken, ..span });
+                }
+                span.len -= taken;
+                if span.len > 0 {
+                    current = Some(span);
+                }
+            }
+        }
+    }
+
+    /// Attributes each line to its latest change, merging consecutive lines of the same revision.
+    pub fn blame(&self, file: &File) -> Vec<BlameRange> {
+        let mut ranges: Vec<BlameRange> = vec![];
+        let mut spans = self.spans.iter();
+        let mut span = spans.next().copied();
+        let mut span_start = 0;
+        for line in 0..file.len_lines() {
+            let line_end = if line + 1 < file.len_lines() {
+                file.line_to_char(line + 1) as u64
+            } else {
+                file.len_chars() as u64
+            };
+            let mut latest: Option<Span> = None;
+            while let Some(current) = span {
+                if latest.is_none_or(|latest| current.rev_num > latest.rev_num) {
+                    latest = Some(current);
+                }
+                if span_start + current.len > line_end {
+                    break;
+                }
+                span_start += current.len;
+                span = spans.next().copied();
+                if span_start == line_end {
+                    break;
+                }
+            }
+            let Some(latest) = latest else {
+                break;
+            };
+            match ranges.last_mut() {
+                Some(last) if (last.author, last.rev_num) == (latest.author, latest.rev_num) => {
+                    last.end = line + 1
+                }
+                _ => ranges.push(BlameRange {
+                    start: line,
+                    end: line + 1,
+                    author: latest.author,
+                    rev_num: latest.rev_num,
+                }),
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blame_lines() {
+        let mut file = File::new("one\ntwo\nthree\n");
+        let mut authorship = Authorship::new(file.len_chars() as u64, 0);
+
+        // replaces "two" and appends a line
+        let mut delta = OperationSeq::default();
+        delta.retain(4);
+        delta.delete(3);
+        delta.insert("2");
+        delta.retain(7);
+        delta.insert("four");
+        file.apply(&delta).unwrap();
+        authorship.apply(&delta, 1, 1);
+
+        let mut delta = OperationSeq::default();
+        delta.retain(11);
+        delta.insert("3");
+        delta.retain(5);
+        file.apply(&delta).unwrap();
+        authorship.apply(&delta, 2, 2);
+
+        assert_eq!(file.to_string(), "one\n2\nthree3\nfour");
+        assert_eq!(
+            authorship.blame(&file),
+            vec![
+                BlameRange {
+                    start: 0,
+                    end: 1,
+                    author: 0,
+                    rev_num: 0,
+                },
+                BlameRange {
+                    start: 1,
+                    end: 2,
+                    author: 1,
+                    rev_num: 1,
+                },
+                BlameRange {
+                    start: 2,
+                    end: 3,
+                    author: 2,
+                    rev_num: 2,
+                },
+                BlameRange {
+                    start: 3,
+                    end: 4,
+                    author: 1,
+                    rev_num: 1,
+                },
+            ]
+        );
+    }
+}
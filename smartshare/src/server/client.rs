@@ -1,15 +1,21 @@
-use smartshare::protocol::msg::MessageServer;
 use tokio::sync::mpsc;
 
+use crate::protocol::msg::MessageServer;
+
 #[derive(Clone)]
 pub struct Client {
     id: usize,
+    identity: String,
     sender: mpsc::Sender<MessageServer>,
 }
 
 impl Client {
-    pub fn new(id: usize, sender: mpsc::Sender<MessageServer>) -> Self {
-        Self { id, sender }
+    pub fn new(id: usize, identity: String, sender: mpsc::Sender<MessageServer>) -> Self {
+        Self {
+            id,
+            identity,
+            sender,
+        }
     }
 
     pub async fn send(&self, message: MessageServer) -> anyhow::Result<()> {
@@ -19,4 +25,8 @@ impl Client {
     pub fn id(&self) -> usize {
         self.id
     }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
 }
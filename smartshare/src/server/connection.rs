@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::SinkExt;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{error, warn};
+
+use crate::protocol::msg::{JoinRequest, MessageServer};
+use crate::protocol::{message_sink, message_stream};
+use crate::server::client::Client;
+use crate::server::limiter::{ClientLimiter, RateLimits, Verdict};
+use crate::server::metrics::Metrics;
+use crate::server::server::ServerHandle;
+
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    pub room: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Access {
+    pub fn admit(&self, join: &JoinRequest) -> Result<(), &'static str> {
+        if let (Some(room), Some(requested)) = (&self.room, &join.room) {
+            if room != requested {
+                return Err("Unknown room");
+            }
+        }
+        if let Some(token) = &self.token {
+            let admitted = join
+                .token
+                .as_deref()
+                .is_some_and(|given| same_secret(token, given));
+            if !admitted {
+                return Err("Invalid token");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares secrets in a time telling nothing about them, not even their length.
+fn same_secret(secret: &str, given: &str) -> bool {
+    let secret = Sha256::digest(secret.as_bytes());
+    let given = Sha256::digest(given.as_bytes());
+    secret.ct_eq(&given).into()
+}
+
+pub async fn accept(
+    listener: TcpListener,
+    handle: ServerHandle,
+    access: Access,
+    limits: RateLimits,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                tokio::spawn(connection(
+                    socket,
+                    handle.clone(),
+                    access.clone(),
+                    limits,
+                    metrics.clone(),
+                ));
+            }
+            Err(err) => error!("Could not accept connection: {err}"),
+        }
+    }
+}
+
+async fn connection(
+    socket: TcpStream,
+    handle: ServerHandle,
+    access: Access,
+    limits: RateLimits,
+    metrics: Arc<Metrics>,
+) {
+    let identity = socket
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let (read, write) = tokio::io::split(socket);
+    let (tx, rx) = mpsc::channel(8);
+
+    tokio::spawn(async move {
+        let mut sink = message_sink(write);
+        let mut rx_stream = ReceiverStream::new(rx).map(Ok);
+        let _ = sink.send_all(&mut rx_stream).await;
+    });
+
+    let current_id = handle.next_id();
+    let errors = tx.clone();
+    let mut stream = message_stream(read);
+
+    let join = tokio::time::timeout(Duration::from_secs(10), stream.next()).await;
+    let Ok(Some(Ok(MessageServer::Join(join)))) = join else {
+        warn!("Client {current_id} did not join");
+        let _ = errors
+            .send(MessageServer::Error {
+                error: "Expected a join message".into(),
+            })
+            .await;
+        return;
+    };
+    if let Err(error) = access.admit(&join) {
+        warn!("Client {current_id} refused: {error}");
+        let _ = errors
+            .send(MessageServer::Error {
+                error: error.into(),
+            })
+            .await;
+        return;
+    }
+
+    handle
+        .on_connect(Client::new(current_id, identity, tx), join)
+        .await;
+
+    let mut limiter = ClientLimiter::new(limits, Instant::now());
+    let mut pending_cursor = None;
+    let mut pending_viewport = None;
+    let mut flush = tokio::time::interval(Duration::from_secs_f64(1.0 / limits.cursor.rate));
+    loop {
+        select! {
+            message = stream.next() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                match limiter.check(&message, Instant::now()) {
+                    Verdict::Accept => {
+                        metrics.accepted();
+                        handle.on_message(current_id, message).await;
+                    }
+                    Verdict::Coalesce => {
+                        metrics.coalesced();
+                        if matches!(message, MessageServer::Viewport(_)) {
+                            pending_viewport = Some(message);
+                        } else {
+                            pending_cursor = Some(message);
+                        }
+                    }
+                    // The author of a dropped update would wait for its ack forever, it rather
+                    // resumes the session once reconnected
+                    Verdict::Reject if matches!(message, MessageServer::ServerUpdate(_)) => {
+                        warn!("Disconnecting client {current_id}: update rate limit exceeded");
+                        metrics.disconnected();
+                        let _ = errors
+                            .send(MessageServer::Error {
+                                error: "Update rate limit exceeded, disconnecting".into(),
+                            })
+                            .await;
+                        break;
+                    }
+                    Verdict::Reject => {
+                        metrics.rejected();
+                        let _ = errors
+                            .send(MessageServer::Error {
+                                error: "Rate limit exceeded, message dropped".into(),
+                            })
+                            .await;
+                    }
+                    Verdict::Disconnect => {
+                        warn!("Disconnecting client {current_id}: rate limit exceeded");
+                        metrics.disconnected();
+                        let _ = errors
+                            .send(MessageServer::Error {
+                                error: "Rate limit exceeded, disconnecting".into(),
+                            })
+                            .await;
+                        break;
+                    }
+                }
+            }
+            _ = flush.tick(), if pending_cursor.is_some() || pending_viewport.is_some() => {
+                for pending in [&mut pending_cursor, &mut pending_viewport] {
+                    if pending.is_some() && limiter.try_cursor(Instant::now()) {
+                        if let Some(message) = pending.take() {
+                            handle.on_message(current_id, message).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    handle.on_disconnect(current_id).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn admit_with_token() {
+        let access = Access {
+            room: None,
+            token: Some("s3cret".into()),
+        };
+        let join = |token: Option<&str>| JoinRequest {
+            token: token.map(Into::into),
+            ..Default::default()
+        };
+
+        assert_eq!(access.admit(&join(Some("s3cret"))), Ok(()));
+        assert_eq!(access.admit(&join(Some("s3cre"))), Err("Invalid token"));
+        assert_eq!(access.admit(&join(None)), Err("Invalid token"));
+    }
+}
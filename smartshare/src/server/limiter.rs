@@ -0,0 +1,211 @@
+use std::time::Instant;
+
+use crate::protocol::msg::MessageServer;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+pub const DEFAULT_UPDATE_LIMIT: Limit = Limit {
+    rate: 50.0,
+    burst: 100.0,
+};
+pub const DEFAULT_CURSOR_LIMIT: Limit = Limit {
+    rate: 20.0,
+    burst: 20.0,
+};
+pub const DEFAULT_OTHER_LIMIT: Limit = Limit {
+    rate: 10.0,
+    burst: 20.0,
+};
+pub const DEFAULT_MAX_STRIKES: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub update: Limit,
+    pub cursor: Limit,
+    pub other: Limit,
+    pub max_strikes: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            update: DEFAULT_UPDATE_LIMIT,
+            cursor: DEFAULT_CURSOR_LIMIT,
+            other: DEFAULT_OTHER_LIMIT,
+            max_strikes: DEFAULT_MAX_STRIKES,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last: now,
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Coalesce,
+    Reject,
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub struct ClientLimiter {
+    update: TokenBucket,
+    cursor: TokenBucket,
+    other: TokenBucket,
+    max_strikes: f64,
+    strikes: f64,
+    last_strike: Instant,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: RateLimits, now: Instant) -> Self {
+        Self {
+            update: TokenBucket::new(limits.update, now),
+            cursor: TokenBucket::new(limits.cursor, now),
+            other: TokenBucket::new(limits.other, now),
+            max_strikes: limits.max_strikes,
+            strikes: 0.0,
+            last_strike: now,
+        }
+    }
+
+    pub fn check(&mut self, message: &MessageServer, now: Instant) -> Verdict {
+        let bucket = match message {
+            MessageServer::ServerUpdate(_) => &mut self.update,
+            MessageServer::Cursor(_) | MessageServer::Viewport(_) => {
+                // Only the latest cursor position or viewport matters, extra ones are held back
+                // instead of being refused.
+                return if self.cursor.try_take(now) {
+                    Verdict::Accept
+                } else {
+                    Verdict::Coalesce
+                };
+            }
+            _ => &mut self.other,
+        };
+
+        if bucket.try_take(now) {
+            return Verdict::Accept;
+        }
+
+        // Strikes are forgiven at one per second so only sustained abuse leads to a disconnection
+        let elapsed = now
+            .saturating_duration_since(self.last_strike)
+            .as_secs_f64();
+        self.strikes = (self.strikes - elapsed).max(0.0) + 1.0;
+        self.last_strike = now;
+        if self.strikes > self.max_strikes {
+            Verdict::Disconnect
+        } else {
+            Verdict::Reject
+        }
+    }
+
+    pub fn try_cursor(&mut self, now: Instant) -> bool {
+        self.cursor.try_take(now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::protocol::msg::{CursorsInfo, ModifRequest};
+
+    use super::*;
+
+    fn limits() -> RateLimits {
+        let limit = Limit {
+            rate: 1.0,
+            burst: 2.0,
+        };
+        RateLimits {
+            update: limit,
+            cursor: limit,
+            other: limit,
+            max_strikes: 2.0,
+        }
+    }
+
+    fn update() -> MessageServer {
+        MessageServer::ServerUpdate(ModifRequest {
+            delta: Default::default(),
+            rev_num: 0,
+            sealed: None,
+        })
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let now = Instant::now();
+        let mut limiter = ClientLimiter::new(limits(), now);
+
+        assert_eq!(limiter.check(&update(), now), Verdict::Accept);
+        assert_eq!(limiter.check(&update(), now), Verdict::Accept);
+        assert_eq!(limiter.check(&update(), now), Verdict::Reject);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(&update(), later), Verdict::Accept);
+    }
+
+    #[test]
+    fn coalesce_cursors() {
+        let now = Instant::now();
+        let mut limiter = ClientLimiter::new(limits(), now);
+        let cursor = MessageServer::Cursor(CursorsInfo {
+            id: None,
+            cursors: vec![],
+            rev_num: None,
+        });
+
+        assert_eq!(limiter.check(&cursor, now), Verdict::Accept);
+        assert_eq!(limiter.check(&cursor, now), Verdict::Accept);
+        assert_eq!(limiter.check(&cursor, now), Verdict::Coalesce);
+        assert_eq!(limiter.check(&cursor, now), Verdict::Coalesce);
+        assert!(!limiter.try_cursor(now));
+        assert!(limiter.try_cursor(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn disconnect_sustained_abuse() {
+        let now = Instant::now();
+        let mut limiter = ClientLimiter::new(limits(), now);
+
+        assert_eq!(limiter.check(&update(), now), Verdict::Accept);
+        assert_eq!(limiter.check(&update(), now), Verdict::Accept);
+        assert_eq!(limiter.check(&update(), now), Verdict::Reject);
+        assert_eq!(limiter.check(&update(), now), Verdict::Reject);
+        assert_eq!(limiter.check(&update(), now), Verdict::Disconnect);
+    }
+}
@@ -1,27 +1,134 @@
-use futures::SinkExt;
-use smartshare::protocol::message_sink;
-use smartshare::protocol::message_stream;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use smartshare::invite::{local_host, Invite, DEFAULT_PORT};
+use smartshare::server::connection::{self, Access};
+use smartshare::server::limiter::{
+    Limit, RateLimits, DEFAULT_CURSOR_LIMIT, DEFAULT_MAX_STRIKES, DEFAULT_OTHER_LIMIT,
+    DEFAULT_UPDATE_LIMIT,
+};
+use smartshare::server::metrics::Metrics;
+use smartshare::server::recording;
+use smartshare::server::server::Server;
 use tokio::net::TcpSocket;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
-use tokio_stream::StreamExt;
-use tracing::info;
-use tracing::instrument::WithSubscriber;
+use tracing::{error, info};
 use tracing::level_filters::LevelFilter;
-use tracing::trace;
 use tracing_subscriber::fmt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
-use crate::client::Client;
-use crate::server::Server;
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// port to listen on
+    #[arg(short, long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+
+    /// name of the shared room, advertised in the invite
+    #[arg(long)]
+    room: Option<String>,
+
+    /// token the participants must present to join
+    #[arg(long)]
+    token: Option<String>,
+
+    /// address the participants should connect to, guessed when not provided
+    #[arg(long)]
+    public_host: Option<String>,
+
+    /// updates allowed per second for each client
+    #[arg(long, default_value_t = DEFAULT_UPDATE_LIMIT.rate, value_parser = positive)]
+    update_rate: f64,
+
+    /// updates a client can send in a burst
+    #[arg(long, default_value_t = DEFAULT_UPDATE_LIMIT.burst, value_parser = positive)]
+    update_burst: f64,
+
+    /// cursor moves forwarded per second for each client, extra ones are coalesced
+    #[arg(long, default_value_t = DEFAULT_CURSOR_LIMIT.rate, value_parser = positive)]
+    cursor_rate: f64,
+
+    /// cursor moves a client can send in a burst
+    #[arg(long, default_value_t = DEFAULT_CURSOR_LIMIT.burst, value_parser = positive)]
+    cursor_burst: f64,
+
+    /// other messages allowed per second for each client
+    #[arg(long, default_value_t = DEFAULT_OTHER_LIMIT.rate, value_parser = positive)]
+    message_rate: f64,
+
+    /// other messages a client can send in a burst
+    #[arg(long, default_value_t = DEFAULT_OTHER_LIMIT.burst, value_parser = positive)]
+    message_burst: f64,
 
-pub mod client;
-pub mod server;
+    /// rejected messages tolerated before disconnecting a client, one is forgiven every second
+    #[arg(long, default_value_t = DEFAULT_MAX_STRIKES)]
+    max_strikes: f64,
+
+    /// file to which every accepted revision is appended as a json line
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// file to which the accepted revisions and cursor moves are appended with their timestamps
+    #[arg(long, conflicts_with = "playback")]
+    record: Option<PathBuf>,
+
+    /// recording to replay to the participants, read-only, instead of hosting a live session
+    #[arg(long)]
+    playback: Option<PathBuf>,
+
+    /// speed multiplier of the playback
+    #[arg(long, default_value_t = 1.0, value_parser = positive)]
+    speed: f64,
+
+    /// seconds between two metrics reports
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    metrics_interval: u64,
+}
+
+fn positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        Ok(_) => Err("should be strictly positive".into()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Opens a file to append to, the server cannot start without it.
+async fn open_append(path: &Path) -> tokio::fs::File {
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await;
+    file.unwrap_or_else(|err| {
+        error!("Could not open {}: {err}", path.display());
+        std::process::exit(1);
+    })
+}
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    let limits = RateLimits {
+        update: Limit {
+            rate: args.update_rate,
+            burst: args.update_burst,
+        },
+        cursor: Limit {
+            rate: args.cursor_rate,
+            burst: args.cursor_burst,
+        },
+        other: Limit {
+            rate: args.message_rate,
+            burst: args.message_burst,
+        },
+        max_strikes: args.max_strikes,
+    };
+
     let env_filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
         .from_env_lossy();
@@ -33,45 +140,71 @@ async fn main() {
     let socket = TcpSocket::new_v4().unwrap();
     info!("Binding socket");
     socket.set_reuseport(true).unwrap();
-    let addr = "0.0.0.0:4903".parse().unwrap();
+    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
     socket.bind(addr).unwrap();
 
     let listener = socket.listen(8).unwrap();
     info!("Listening on {}", addr);
 
+    let host = args
+        .public_host
+        .clone()
+        .or_else(local_host)
+        .unwrap_or_else(|| "127.0.0.1".into());
+    let invite = Invite {
+        host,
+        port: args.port,
+        room: args.room.clone(),
+        token: args.token.clone(),
+        fingerprint: None,
+    };
+    info!("Invite: {}", invite.redacted());
+    println!("{invite}");
+
+    let access = Access {
+        room: args.room,
+        token: args.token,
+    };
+
     let (mut server, server_handle) = Server::new();
+    if let Some(path) = &args.audit_log {
+        server = server.with_audit_log(open_append(path).await);
+    }
+    if let Some(path) = &args.record {
+        server = server.with_recording(open_append(path).await);
+    }
+    if let Some(path) = &args.playback {
+        let events = recording::load(path).await.unwrap_or_else(|err| {
+            error!("Could not load the recording {}: {err}", path.display());
+            std::process::exit(1);
+        });
+        // The replayed participants keep their ids, viewers get the following ones
+        if let Some(last) = events.iter().map(|event| event.author).max() {
+            server_handle.reserve_ids(last + 1);
+        }
+        let (start, started) = tokio::sync::oneshot::channel();
+        server = server.with_playback(start);
+        tokio::spawn(recording::play(
+            events,
+            server_handle.clone(),
+            args.speed,
+            started,
+        ));
+    }
     tokio::spawn(async move {
         server.run().await;
     });
 
-    let mut id = 0;
-    loop {
-        let (socket, _peer_addr) = listener.accept().await.unwrap();
-
-        let (tx, rx) = mpsc::channel(8);
-
-        let (read, write) = tokio::io::split(socket);
-
-        let current_id = id;
-        id += 1;
-
-        let client = Client::new(current_id, tx);
-        server_handle.on_connect(client).await;
-
-        let handle = server_handle.clone();
-
-        tokio::spawn(async move {
-            let mut stream = message_stream(read);
-            while let Some(Ok(message)) = stream.next().await {
-                handle.on_message(current_id, message).await;
-            }
-            handle.on_disconnect(current_id).await;
-        });
+    let metrics = Arc::new(Metrics::default());
+    let reporter = metrics.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(args.metrics_interval));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            reporter.report(&limits);
+        }
+    });
 
-        tokio::spawn(async move {
-            let mut sink = message_sink(write);
-            let mut rx_stream = ReceiverStream::new(rx).map(Ok);
-            let _ = sink.send_all(&mut rx_stream).await;
-        });
-    }
+    connection::accept(listener, server_handle, access, limits, metrics).await;
 }
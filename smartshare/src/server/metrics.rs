@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::info;
+
+use crate::server::limiter::RateLimits;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    coalesced: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl Metrics {
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.disconnected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, limits: &RateLimits) {
+        info!(
+            accepted = self.accepted.load(Ordering::Relaxed),
+            rejected = self.rejected.load(Ordering::Relaxed),
+            coalesced = self.coalesced.load(Ordering::Relaxed),
+            disconnected = self.disconnected.load(Ordering::Relaxed),
+            update_rate = limits.update.rate,
+            update_burst = limits.update.burst,
+            cursor_rate = limits.cursor.rate,
+            cursor_burst = limits.cursor.burst,
+            other_rate = limits.other.rate,
+            other_burst = limits.other.burst,
+            max_strikes = limits.max_strikes,
+            "Rate limiting metrics"
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::protocol::msg::MessageServer;
+use crate::server::server::ServerHandle;
+
+/// A message accepted by the server, as written to a recording.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecordedEvent {
+    pub timestamp: u64,
+    pub author: usize,
+    pub message: MessageServer,
+}
+
+pub async fn load(path: &Path) -> anyhow::Result<Vec<RecordedEvent>> {
+    let content = tokio::fs::read_to_string(path).await?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+/// Replays the events once `start` fires, `speed` times faster than they were recorded.
+pub async fn play(
+    events: Vec<RecordedEvent>,
+    handle: ServerHandle,
+    speed: f64,
+    start: oneshot::Receiver<()>,
+) {
+    // Everything up to the initial file is loaded right away so that the first viewer gets it
+    // when joining
+    let preloaded = events
+        .iter()
+        .position(|event| matches!(event.message, MessageServer::File { .. }))
+        .map_or(0, |index| index + 1);
+    let Some(first) = events
+        .get(preloaded.saturating_sub(1))
+        .map(|event| event.timestamp)
+    else {
+        return;
+    };
+    let mut events = events.into_iter();
+    for event in events.by_ref().take(preloaded) {
+        handle.on_replay(event.author, event.message).await;
+    }
+    if start.await.is_err() {
+        return;
+    }
+
+    info!("Starting playback");
+    let origin = Instant::now();
+    for event in events {
+        let offset = Duration::from_millis(event.timestamp.saturating_sub(first)).div_f64(speed);
+        tokio::time::sleep_until(origin + offset).await;
+        handle.on_replay(event.author, event.message).await;
+    }
+    info!("Playback finished");
+}
@@ -1,16 +1,86 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure};
 use operational_transform::OperationSeq;
-use smartshare::file::File;
-use smartshare::protocol::msg::{CursorsInfo, MessageServer, ModifRequest};
-use tokio::sync::mpsc;
+use similar::{capture_diff_slices, Algorithm, DiffTag, TextDiff};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{error, info, trace, warn};
 
-use crate::client::Client;
+use crate::crypto;
+use crate::file::File;
+use crate::protocol::msg::{
+    transform_cursors, transform_index, transform_viewports, ActivityInfo, AuditEntry, AuditQuery,
+    ChatMessage, Checkpoint, Comment, CursorsInfo, JoinRequest, MessageServer, ModifRequest,
+    NewThread, Participant, Resume, RevisionDiff, Thread, ViewportInfo,
+};
+use crate::server::blame::Authorship;
+use crate::server::client::Client;
+use crate::server::recording::RecordedEvent;
+
+struct Revision {
+    delta: OperationSeq,
+    sealed: Option<String>,
+    audit: AuditEntry,
+    snapshot: Option<File>,
+}
+
+const COLORS: [&str; 6] = [
+    "#FA8072", "#9ACD32", "#4682B4", "#BA55D3", "#FF8C00", "#00FFFF",
+];
+
+const CHAT_HISTORY: usize = 100;
+const SNAPSHOT_INTERVAL: usize = 100;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The delta turning `old` into `new`.
+fn delta_between(old: &str, new: &str) -> OperationSeq {
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let mut delta = OperationSeq::default();
+    for op in capture_diff_slices(Algorithm::Myers, &old, &new) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        if matches!(tag, DiffTag::Equal) {
+            delta.retain(old_range.len() as u64);
+            continue;
+        }
+        delta.delete(old_range.len() as u64);
+        delta.insert(&new[new_range].iter().collect::<String>());
+    }
+    delta
+}
 
 pub struct Server {
     clients: Vec<Client>,
+    roster: Vec<Participant>,
+    cursors: HashMap<usize, CursorsInfo>,
+    viewports: HashMap<usize, ViewportInfo>,
+    chat: VecDeque<ChatMessage>,
+    threads: Vec<Thread>,
+    checkpoints: Vec<Checkpoint>,
+    activities: HashMap<usize, ActivityInfo>,
+    host: Option<usize>,
+    presenter: Option<usize>,
     receiver: mpsc::Receiver<ServerMessage>,
-    deltas: Vec<OperationSeq>,
+    deltas: Vec<Revision>,
     file: Option<File>,
+    authorship: Authorship,
+    sealed_file: Option<(String, String)>,
+    audit_log: Option<tokio::fs::File>,
+    recording: Option<tokio::fs::File>,
+    playback: Option<oneshot::Sender<()>>,
+    read_only: bool,
+    /// Tells the clients of this run apart from those of another server or a previous run.
+    session: String,
 }
 
 impl Server {
@@ -20,43 +90,272 @@ impl Server {
             Self {
                 deltas: vec![],
                 clients: vec![],
+                roster: vec![],
+                cursors: HashMap::new(),
+                viewports: HashMap::new(),
+                chat: VecDeque::new(),
+                threads: vec![],
+                checkpoints: vec![],
+                activities: HashMap::new(),
+                host: None,
+                presenter: None,
                 receiver: rx,
                 file: None,
+                authorship: Authorship::default(),
+                sealed_file: None,
+                audit_log: None,
+                recording: None,
+                playback: None,
+                read_only: false,
+                session: crypto::random(16),
+            },
+            ServerHandle {
+                sender: tx,
+                next_id: Arc::new(AtomicUsize::new(0)),
             },
-            ServerHandle { sender: tx },
         )
     }
 
+    pub fn with_audit_log(mut self, audit_log: tokio::fs::File) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    pub fn with_recording(mut self, recording: tokio::fs::File) -> Self {
+        self.recording = Some(recording);
+        self
+    }
+
+    /// Turns the server into a read-only replay, `start` firing when the first viewer joins.
+    pub fn with_playback(mut self, start: oneshot::Sender<()>) -> Self {
+        self.playback = Some(start);
+        self.read_only = true;
+        self
+    }
+
     pub async fn run(&mut self) {
         while let Some(message) = self.receiver.recv().await {
             match message {
                 ServerMessage::Message(client_id, message) => {
                     self.on_message(client_id, message).await
                 }
-                ServerMessage::Connect(client) => self.on_connect(client).await,
+                ServerMessage::Connect(client, join) => self.on_connect(client, join).await,
                 ServerMessage::Disctonnect(client_id) => self.on_disconnect(client_id).await,
+                ServerMessage::Replay(author, message) => self.on_replay(author, message).await,
             }
         }
     }
 
-    async fn on_connect(&mut self, client: Client) {
+    async fn on_connect(&mut self, client: Client, mut join: JoinRequest) {
         info!("New client connected: {}", client.id());
-        match &self.file {
-            Some(file) => {
+        if client
+            .send(MessageServer::Welcome {
+                id: client.id(),
+                session: self.session.clone(),
+            })
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let resume = join.resume.take().filter(|resume| self.can_resume(resume));
+        if let Some(resume) = resume {
+            if !self.resume(&client, resume).await {
+                return;
+            }
+        } else if !self.send_file(&client).await {
+            return;
+        }
+        for cursor_info in self
+            .cursors
+            .values()
+            .filter(|info| self.is_relayed(info.id))
+        {
+            let _ = client
+                .send(MessageServer::Cursor(cursor_info.clone()))
+                .await;
+        }
+        for viewport_info in self
+            .viewports
+            .values()
+            .filter(|info| self.is_relayed(info.id))
+        {
+            let _ = client
+                .send(MessageServer::Viewport(viewport_info.clone()))
+                .await;
+        }
+        for activity_info in self.activities.values() {
+            let _ = client
+                .send(MessageServer::Activity(activity_info.clone()))
+                .await;
+        }
+        for message in &self.chat {
+            let _ = client.send(MessageServer::Chat(message.clone())).await;
+        }
+        if let Some(start) = self.playback.take() {
+            let _ = start.send(());
+        }
+        if !self.checkpoints.is_empty() {
+            let _ = client
+                .send(MessageServer::Checkpoints {
+                    checkpoints: self.checkpoints.clone(),
+                })
+                .await;
+        }
+
+        let participant = Participant {
+            id: client.id(),
+            name: join
+                .name
+                .filter(|name| !name.trim().is_empty())
+                .unwrap_or_else(|| format!("Guest {}", client.id())),
+            color: join
+                .color
+                .unwrap_or_else(|| COLORS[client.id() % COLORS.len()].to_owned()),
+        };
+        self.broadcast(MessageServer::Joined(participant.clone()))
+            .await;
+        self.record(participant.id, MessageServer::Joined(participant.clone()))
+            .await;
+        self.clients.push(client);
+        self.roster.push(participant);
+        self.broadcast_roster().await;
+        if let (Some(client), Some(presenter)) = (self.clients.last(), self.presenter) {
+            let _ = client
+                .send(MessageServer::PresenterChanged {
+                    id: Some(presenter),
+                })
+                .await;
+        }
+    }
+
+    /// Sends the document to a joining client, or asks it for one if there is none yet.
+    async fn send_file(&self, client: &Client) -> bool {
+        match (&self.file, &self.sealed_file) {
+            (Some(_), Some((file, sealed))) => {
+                // The server cannot read an end-to-end encrypted file, so the client rebuilds it
+                // from the initial snapshot and every revision since.
+                let _ = client
+                    .send(MessageServer::File {
+                        file: file.clone(),
+                        version: 0,
+                        sealed: Some(sealed.clone()),
+                    })
+                    .await;
+                for (rev_num, revision) in self.deltas.iter().enumerate().skip(1) {
+                    let _ = client
+                        .send(MessageServer::ServerUpdate(ModifRequest {
+                            delta: revision.delta.clone(),
+                            rev_num,
+                            sealed: revision.sealed.clone(),
+                        }))
+                        .await;
+                }
+            }
+            (Some(file), None) => {
                 let _ = client
                     .send(MessageServer::File {
                         file: file.to_string(),
                         version: self.deltas.len() - 1,
+                        sealed: None,
                     })
                     .await;
             }
-            None => {
-                if client.send(MessageServer::RequestFile).await.is_err() {
-                    return;
+            // Viewers of a playback get the file once it is replayed
+            (None, _) if self.read_only => (),
+            (None, _) => return client.send(MessageServer::RequestFile).await.is_ok(),
+        }
+        true
+    }
+
+    /// Only a client of this very session may resume, the others get the file like a new one.
+    fn can_resume(&self, resume: &Resume) -> bool {
+        if resume.session != self.session {
+            info!("Cannot resume a client of another session");
+            return false;
+        }
+        if resume.rev_num >= self.deltas.len() {
+            info!("Cannot resume from unknown revision {}", resume.rev_num);
+            return false;
+        }
+        true
+    }
+
+    /// Sends a client back after losing its connection every revision it missed, its own ones
+    /// being acknowledged instead.
+    async fn resume(&self, client: &Client, resume: Resume) -> bool {
+        for (rev_num, revision) in self.deltas.iter().enumerate().skip(resume.rev_num + 1) {
+            let message = if revision.audit.author == resume.id {
+                MessageServer::Ack
+            } else {
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: revision.delta.clone(),
+                    rev_num,
+                    sealed: revision.sealed.clone(),
+                })
+            };
+            if client.send(message).await.is_err() {
+                return false;
+            }
+        }
+        client.send(MessageServer::Resumed).await.is_ok()
+    }
+
+    /// While presenting, only the presenter's cursors and viewports are relayed.
+    fn is_relayed(&self, client_id: Option<usize>) -> bool {
+        self.presenter.is_none() || self.presenter == client_id
+    }
+
+    async fn on_present(&mut self, source_id: usize, enabled: bool) {
+        if self.host != Some(source_id) {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: "Only the host can present".into(),
+                },
+            )
+            .await;
+            return;
+        }
+        self.presenter = enabled.then_some(source_id);
+        self.broadcast(MessageServer::PresenterChanged { id: self.presenter })
+            .await;
+        if !enabled {
+            self.relay_presence().await;
+        }
+    }
+
+    /// Sends the cursors and viewports held back during a presentation.
+    async fn relay_presence(&self) {
+        for client in &self.clients {
+            for cursor_info in self.cursors.values() {
+                if cursor_info.id != Some(client.id()) {
+                    let _ = client
+                        .send(MessageServer::Cursor(cursor_info.clone()))
+                        .await;
+                }
+            }
+            for viewport_info in self.viewports.values() {
+                if viewport_info.id != Some(client.id()) {
+                    let _ = client
+                        .send(MessageServer::Viewport(viewport_info.clone()))
+                        .await;
                 }
             }
         }
-        self.clients.push(client);
+    }
+
+    async fn broadcast(&self, message: MessageServer) {
+        for client in &self.clients {
+            let _ = client.send(message.clone()).await;
+        }
+    }
+
+    async fn broadcast_roster(&self) {
+        self.broadcast(MessageServer::Roster {
+            participants: self.roster.clone(),
+        })
+        .await;
     }
 
     async fn send_to_client(&self, client_id: usize, message: MessageServer) {
@@ -68,6 +367,19 @@ impl Server {
     async fn on_disconnect(&mut self, client_id: usize) {
         info!("Client disconnected: {client_id}");
         self.clients.retain(|client| client.id() != client_id);
+        self.roster
+            .retain(|participant| participant.id != client_id);
+        self.cursors.remove(&client_id);
+        self.viewports.remove(&client_id);
+        self.activities.remove(&client_id);
+        if self.presenter == Some(client_id) {
+            self.presenter = None;
+            self.broadcast(MessageServer::PresenterChanged { id: None })
+                .await;
+            self.relay_presence().await;
+        }
+        self.broadcast(MessageServer::Left { id: client_id }).await;
+        self.broadcast_roster().await;
     }
 
     async fn on_update(&mut self, source_id: usize, req: ModifRequest) {
@@ -89,30 +401,83 @@ impl Server {
         } else {
             let mut delta_p = req.delta;
             for i in req.rev_num + 1..self.deltas.len() {
-                (_, delta_p) = self.deltas[i].transform(&delta_p).unwrap();
+                (_, delta_p) = self.deltas[i].delta.transform(&delta_p).unwrap();
             }
             file.apply(&delta_p).unwrap();
-            self.deltas.push(delta_p.clone());
-            for client in self.clients.iter() {
-                let notif = if client.id() == source_id {
-                    MessageServer::Ack
-                } else {
-                    MessageServer::ServerUpdate(ModifRequest {
-                        delta: delta_p.clone(),
-                        rev_num: self.deltas.len() - 1,
-                    })
-                };
-                if client.send(notif).await.is_err() {
-                    warn!(
-                        "Could not send message to client {}. Maybe it is disconnected ?",
-                        client.id()
-                    );
-                }
+            self.commit(source_id, delta_p, req.sealed, true).await;
+        }
+    }
+
+    /// Records a delta already applied to the file and sends it to everyone who does not have it
+    /// yet, `applied` telling whether its author already does.
+    async fn commit(
+        &mut self,
+        author: usize,
+        delta: OperationSeq,
+        sealed: Option<String>,
+        applied: bool,
+    ) {
+        self.authorship.apply(&delta, author, self.deltas.len());
+        self.push_revision(author, delta.clone(), sealed.clone())
+            .await;
+        let rev_num = self.deltas.len() - 1;
+        self.record(
+            author,
+            MessageServer::ServerUpdate(ModifRequest {
+                delta: delta.clone(),
+                rev_num,
+                sealed: sealed.clone(),
+            }),
+        )
+        .await;
+        for (&client_id, cursor_info) in self.cursors.iter_mut() {
+            // The author's cursors already take its own modifications into account
+            if !applied || client_id != author {
+                transform_cursors(&delta, &mut cursor_info.cursors);
+            }
+            cursor_info.rev_num = Some(rev_num);
+        }
+        for (&client_id, viewport_info) in self.viewports.iter_mut() {
+            if !applied || client_id != author {
+                transform_viewports(&delta, &mut viewport_info.viewports);
+            }
+            viewport_info.rev_num = Some(rev_num);
+        }
+        for message in self.chat.iter_mut() {
+            message.anchor = message.anchor.map(|anchor| transform_index(&delta, anchor));
+            message.rev_num = Some(rev_num);
+        }
+        for thread in self.threads.iter_mut() {
+            thread.start = transform_index(&delta, thread.start);
+            thread.end = transform_index(&delta, thread.end);
+            thread.rev_num = Some(rev_num);
+        }
+        for client in self.clients.iter() {
+            let notif = if applied && client.id() == author {
+                MessageServer::Ack
+            } else {
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: delta.clone(),
+                    rev_num,
+                    sealed: sealed.clone(),
+                })
+            };
+            if client.send(notif).await.is_err() {
+                warn!(
+                    "Could not send message to client {}. Maybe it is disconnected ?",
+                    client.id()
+                );
             }
         }
     }
 
-    async fn on_file(&mut self, source_id: usize, file: String, version: usize) {
+    async fn on_file(
+        &mut self,
+        source_id: usize,
+        file: String,
+        version: usize,
+        sealed: Option<String>,
+    ) {
         if version != 0 {
             self.send_to_client(
                 source_id,
@@ -135,17 +500,286 @@ impl Server {
             return;
         }
 
+        self.record(
+            source_id,
+            MessageServer::File {
+                file: file.clone(),
+                version,
+                sealed: sealed.clone(),
+            },
+        )
+        .await;
+        self.host = Some(source_id);
+        self.sealed_file = sealed.map(|sealed| (file.clone(), sealed));
         let file = File::new(&file);
 
         let mut delta = OperationSeq::default();
         delta.retain(file.len_chars() as u64);
-        self.deltas.push(delta);
-
+        self.authorship = Authorship::new(file.len_chars() as u64, source_id);
         self.file = Some(file);
+        self.push_revision(source_id, delta, None).await;
+    }
+
+    async fn push_revision(&mut self, author: usize, delta: OperationSeq, sealed: Option<String>) {
+        let identity = self
+            .clients
+            .iter()
+            .find(|client| client.id() == author)
+            .map(|client| client.identity().to_owned())
+            .unwrap_or_default();
+        let audit = AuditEntry {
+            rev_num: self.deltas.len(),
+            author,
+            identity,
+            name: self.name_of(author),
+            timestamp: now(),
+        };
+
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            let mut line = serde_json::to_vec(&audit).expect("audit entry should serialize");
+            line.push(b'\n');
+            if let Err(err) = audit_log.write_all(&line).await {
+                error!("Could not write to the audit log: {err}");
+            }
+            // Flushed right away so that a crash does not lose the latest revisions
+            if let Err(err) = audit_log.flush().await {
+                error!("Could not write to the audit log: {err}");
+            }
+        }
+
+        // Old revisions are rebuilt from the closest snapshot before them
+        let snapshot = if self.deltas.len().is_multiple_of(SNAPSHOT_INTERVAL) {
+            self.file.clone()
+        } else {
+            None
+        };
+        self.deltas.push(Revision {
+            delta,
+            sealed,
+            audit,
+            snapshot,
+        });
+    }
+
+    fn revision(&self, rev_num: usize) -> anyhow::Result<String> {
+        ensure!(
+            self.sealed_file.is_none(),
+            "Past revisions of an end-to-end encrypted file cannot be read by the server"
+        );
+        ensure!(rev_num < self.deltas.len(), "Unknown revision {rev_num}");
+        let base = rev_num - rev_num % SNAPSHOT_INTERVAL;
+        let mut file = self.deltas[base]
+            .snapshot
+            .clone()
+            .ok_or_else(|| anyhow!("Missing snapshot of revision {base}"))?;
+        for revision in &self.deltas[base + 1..=rev_num] {
+            file.apply(&revision.delta)?;
+        }
+        Ok(file.to_string())
+    }
+
+    async fn on_request_blame(&mut self, source_id: usize) {
+        let message = match (&self.file, &self.sealed_file) {
+            (Some(file), None) => MessageServer::Blame {
+                ranges: self.authorship.blame(file),
+            },
+            (Some(_), Some(_)) => MessageServer::Error {
+                error: "The lines of an end-to-end encrypted file cannot be read by the server"
+                    .into(),
+            },
+            (None, _) => MessageServer::Error {
+                error: "File not initialized".into(),
+            },
+        };
+        self.send_to_client(source_id, message).await;
+    }
+
+    fn diff(&self, from: usize, to: usize) -> anyhow::Result<RevisionDiff> {
+        ensure!(from <= to, "Revision {from} comes after revision {to}");
+        ensure!(to < self.deltas.len(), "Unknown revision {to}");
+        let old = self.revision(from)?;
+        let mut delta = OperationSeq::default();
+        delta.retain(old.chars().count() as u64);
+        for revision in &self.deltas[from + 1..=to] {
+            delta = delta.compose(&revision.delta)?;
+        }
+        let new = delta.apply(&old)?;
+        let unified = TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .header(&format!("revision {from}"), &format!("revision {to}"))
+            .to_string();
+        Ok(RevisionDiff {
+            from,
+            to,
+            delta,
+            unified,
+        })
+    }
+
+    async fn on_checkpoint(&mut self, source_id: usize, name: String) {
+        if self.file.is_none() {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: "File not initialized".into(),
+                },
+            )
+            .await;
+            return;
+        }
+        if self
+            .checkpoints
+            .iter()
+            .any(|checkpoint| checkpoint.name == name)
+        {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: format!("Checkpoint {name} already exists"),
+                },
+            )
+            .await;
+            return;
+        }
+
+        self.checkpoints.push(Checkpoint {
+            name,
+            rev_num: self.deltas.len() - 1,
+            author: source_id,
+            timestamp: now(),
+        });
+        self.broadcast(MessageServer::Checkpoints {
+            checkpoints: self.checkpoints.clone(),
+        })
+        .await;
+    }
+
+    /// Restores an earlier revision with a new delta so that the history is kept.
+    async fn revert(&mut self, source_id: usize, rev_num: usize) -> anyhow::Result<()> {
+        let target = self.revision(rev_num)?;
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("File not initialized"))?;
+        let delta = delta_between(&file.to_string(), &target);
+        file.apply(&delta)?;
+        self.commit(source_id, delta, None, false).await;
+        Ok(())
+    }
+
+    async fn on_revert(&mut self, source_id: usize, rev_num: usize) {
+        if let Err(err) = self.revert(source_id, rev_num).await {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: err.to_string(),
+                },
+            )
+            .await;
+        }
+    }
+
+    async fn on_request_diff(&mut self, source_id: usize, from: usize, to: usize) {
+        let message = match self.diff(from, to) {
+            Ok(diff) => MessageServer::Diff(diff),
+            Err(err) => MessageServer::Error {
+                error: err.to_string(),
+            },
+        };
+        self.send_to_client(source_id, message).await;
+    }
+
+    async fn on_request_revision(&mut self, source_id: usize, rev_num: usize) {
+        let message = match self.revision(rev_num) {
+            Ok(file) => MessageServer::Revision { rev_num, file },
+            Err(err) => MessageServer::Error {
+                error: err.to_string(),
+            },
+        };
+        self.send_to_client(source_id, message).await;
+    }
+
+    async fn record(&mut self, author: usize, message: MessageServer) {
+        let Some(recording) = self.recording.as_mut() else {
+            return;
+        };
+        let event = RecordedEvent {
+            timestamp: now(),
+            author,
+            message,
+        };
+        let mut line = serde_json::to_vec(&event).expect("recorded event should serialize");
+        line.push(b'\n');
+        if let Err(err) = recording.write_all(&line).await {
+            error!("Could not write to the recording: {err}");
+        }
+        if let Err(err) = recording.flush().await {
+            error!("Could not write to the recording: {err}");
+        }
+    }
+
+    async fn on_replay(&mut self, author: usize, message: MessageServer) {
+        match message {
+            MessageServer::File {
+                file,
+                version,
+                sealed,
+            } => {
+                let pending = self.file.is_none();
+                self.on_file(author, file, version, sealed).await;
+                if pending && self.file.is_some() {
+                    // For the viewers who joined before the file was replayed
+                    for client in &self.clients {
+                        self.send_file(client).await;
+                    }
+                }
+            }
+            MessageServer::ServerUpdate(req) => {
+                let Some(file) = self.file.as_mut() else {
+                    error!("Revision {} replayed before the file", req.rev_num);
+                    return;
+                };
+                if let Err(err) = file.apply(&req.delta) {
+                    error!("Could not replay revision {}: {err}", req.rev_num);
+                    return;
+                }
+                self.commit(author, req.delta, req.sealed, false).await;
+            }
+            MessageServer::Cursor(cursor_info) => self.on_cursor_move(author, cursor_info).await,
+            // Only kept for the names of the participants
+            MessageServer::Joined(_) => (),
+            _ => warn!("Cannot replay message {:?}", message),
+        }
+    }
+
+    async fn on_request_audit(&mut self, source_id: usize, query: AuditQuery) {
+        let entries = self
+            .deltas
+            .iter()
+            .map(|revision| &revision.audit)
+            .filter(|entry| query.from.is_none_or(|from| entry.rev_num >= from))
+            .filter(|entry| query.to.is_none_or(|to| entry.rev_num <= to))
+            .filter(|entry| query.author.is_none_or(|author| entry.author == author))
+            .cloned()
+            .collect();
+        self.send_to_client(source_id, MessageServer::Audit { entries })
+            .await;
     }
 
     async fn on_cursor_move(&mut self, source_id: usize, mut cursor_info: CursorsInfo) {
         cursor_info.id = Some(source_id);
+        if let Some(rev_num) = cursor_info.rev_num {
+            for delta in self.deltas_since(rev_num, source_id) {
+                transform_cursors(delta, &mut cursor_info.cursors);
+            }
+        }
+        cursor_info.rev_num = self.deltas.len().checked_sub(1);
+        self.cursors.insert(source_id, cursor_info.clone());
+        self.record(source_id, MessageServer::Cursor(cursor_info.clone()))
+            .await;
+        if !self.is_relayed(Some(source_id)) {
+            return;
+        }
         for client in self
             .clients
             .iter()
@@ -157,13 +791,240 @@ impl Server {
         }
     }
 
+    async fn on_viewport_move(&mut self, source_id: usize, mut viewport_info: ViewportInfo) {
+        viewport_info.id = Some(source_id);
+        if let Some(rev_num) = viewport_info.rev_num {
+            for delta in self.deltas_since(rev_num, source_id) {
+                transform_viewports(delta, &mut viewport_info.viewports);
+            }
+        }
+        viewport_info.rev_num = self.deltas.len().checked_sub(1);
+        self.viewports.insert(source_id, viewport_info.clone());
+        if !self.is_relayed(Some(source_id)) {
+            return;
+        }
+        for client in self
+            .clients
+            .iter()
+            .filter(|client| client.id() != source_id)
+        {
+            let _ = client
+                .send(MessageServer::Viewport(viewport_info.clone()))
+                .await;
+        }
+    }
+
+    async fn on_activity(&mut self, source_id: usize, mut activity_info: ActivityInfo) {
+        activity_info.id = Some(source_id);
+        self.activities.insert(source_id, activity_info.clone());
+        for client in self
+            .clients
+            .iter()
+            .filter(|client| client.id() != source_id)
+        {
+            let _ = client
+                .send(MessageServer::Activity(activity_info.clone()))
+                .await;
+        }
+    }
+
+    async fn on_chat(&mut self, source_id: usize, mut message: ChatMessage) {
+        message.author = Some(source_id);
+        message.name = self.name_of(source_id);
+        message.timestamp = Some(now());
+        if let (Some(anchor), Some(rev_num)) = (message.anchor, message.rev_num) {
+            message.anchor = Some(
+                self.deltas_since(rev_num, source_id)
+                    .fold(anchor, |anchor, delta| transform_index(delta, anchor)),
+            );
+        }
+        message.rev_num = self.deltas.len().checked_sub(1);
+
+        if self.chat.len() == CHAT_HISTORY {
+            self.chat.pop_front();
+        }
+        self.chat.push_back(message.clone());
+        self.broadcast(MessageServer::Chat(message)).await;
+    }
+
+    async fn on_create_thread(&mut self, source_id: usize, new_thread: NewThread) {
+        let len = match new_thread.rev_num {
+            // The range takes the author's own revisions since into account
+            Some(rev_num) => self.deltas.get(rev_num).map(|revision| {
+                self.deltas[rev_num + 1..]
+                    .iter()
+                    .filter(|revision| revision.audit.author == source_id)
+                    .fold(revision.delta.target_len() as u64, |len, revision| {
+                        (len + revision.delta.target_len() as u64)
+                            .saturating_sub(revision.delta.base_len() as u64)
+                    })
+            }),
+            None => self.file.as_ref().map(|file| file.len_chars() as u64),
+        };
+        let (mut start, mut end) = (new_thread.start, new_thread.end);
+        if !len.is_some_and(|len| start <= end && end <= len) {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: "Invalid thread range".into(),
+                },
+            )
+            .await;
+            return;
+        }
+        if let Some(rev_num) = new_thread.rev_num {
+            for delta in self.deltas_since(rev_num, source_id) {
+                start = transform_index(delta, start);
+                end = transform_index(delta, end);
+            }
+        }
+        let thread = Thread {
+            id: self.threads.len(),
+            start,
+            end,
+            comments: vec![self.sign(source_id, new_thread.comment)],
+            resolved: false,
+            rev_num: self.deltas.len().checked_sub(1),
+        };
+        self.threads.push(thread.clone());
+        self.broadcast(MessageServer::ThreadUpdated(thread)).await;
+    }
+
+    async fn on_reply(&mut self, source_id: usize, thread_id: usize, comment: Comment) {
+        let comment = self.sign(source_id, comment);
+        self.update_thread(source_id, thread_id, |thread| {
+            thread.comments.push(comment);
+            thread.resolved = false;
+        })
+        .await;
+    }
+
+    async fn update_thread(
+        &mut self,
+        source_id: usize,
+        thread_id: usize,
+        update: impl FnOnce(&mut Thread),
+    ) {
+        let Some(thread) = self.threads.get_mut(thread_id) else {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: format!("Unknown thread {thread_id}"),
+                },
+            )
+            .await;
+            return;
+        };
+        update(thread);
+        let thread = thread.clone();
+        self.broadcast(MessageServer::ThreadUpdated(thread)).await;
+    }
+
+    fn sign(&self, source_id: usize, mut comment: Comment) -> Comment {
+        comment.author = Some(source_id);
+        comment.name = self.name_of(source_id);
+        comment.timestamp = Some(now());
+        comment
+    }
+
+    fn name_of(&self, client_id: usize) -> Option<String> {
+        self.roster
+            .iter()
+            .find(|participant| participant.id == client_id)
+            .map(|participant| participant.name.clone())
+    }
+
+    /// Deltas accepted after `rev_num`, except the ones from `author` whose positions already
+    /// account for its own modifications.
+    fn deltas_since(&self, rev_num: usize, author: usize) -> impl Iterator<Item = &OperationSeq> {
+        self.deltas
+            .iter()
+            .skip(rev_num + 1)
+            .filter(move |revision| revision.audit.author != author)
+            .map(|revision| &revision.delta)
+    }
+
     async fn on_message(&mut self, source_id: usize, message: MessageServer) {
         trace!("User message: {:?}", message);
 
+        if self.read_only
+            && matches!(
+                message,
+                MessageServer::ServerUpdate(_)
+                    | MessageServer::File { .. }
+                    | MessageServer::Revert { .. }
+                    | MessageServer::RevertToCheckpoint { .. }
+            )
+        {
+            self.send_to_client(
+                source_id,
+                MessageServer::Error {
+                    error: "The session is a read-only playback".into(),
+                },
+            )
+            .await;
+            return;
+        }
+
         match message {
             MessageServer::ServerUpdate(req) => self.on_update(source_id, req).await,
-            MessageServer::File { file, version } => self.on_file(source_id, file, version).await,
+            MessageServer::File {
+                file,
+                version,
+                sealed,
+            } => self.on_file(source_id, file, version, sealed).await,
             MessageServer::Cursor(cursor_info) => self.on_cursor_move(source_id, cursor_info).await,
+            MessageServer::Viewport(viewport_info) => {
+                self.on_viewport_move(source_id, viewport_info).await
+            }
+            MessageServer::Chat(message) => self.on_chat(source_id, message).await,
+            MessageServer::CreateThread(new_thread) => {
+                self.on_create_thread(source_id, new_thread).await
+            }
+            MessageServer::Reply { thread, comment } => {
+                self.on_reply(source_id, thread, comment).await
+            }
+            MessageServer::Resolve { thread } => {
+                self.update_thread(source_id, thread, |thread| thread.resolved = true)
+                    .await
+            }
+            MessageServer::Activity(activity_info) => {
+                self.on_activity(source_id, activity_info).await
+            }
+            MessageServer::Present { enabled } => self.on_present(source_id, enabled).await,
+            MessageServer::ListThreads => {
+                let threads = self.threads.clone();
+                self.send_to_client(source_id, MessageServer::Threads { threads })
+                    .await
+            }
+            MessageServer::RequestAudit(query) => self.on_request_audit(source_id, query).await,
+            MessageServer::Checkpoint { name } => self.on_checkpoint(source_id, name).await,
+            MessageServer::Revert { rev_num } => self.on_revert(source_id, rev_num).await,
+            MessageServer::RevertToCheckpoint { name } => {
+                match self
+                    .checkpoints
+                    .iter()
+                    .find(|checkpoint| checkpoint.name == name)
+                {
+                    Some(checkpoint) => self.on_revert(source_id, checkpoint.rev_num).await,
+                    None => {
+                        self.send_to_client(
+                            source_id,
+                            MessageServer::Error {
+                                error: format!("Unknown checkpoint {name}"),
+                            },
+                        )
+                        .await
+                    }
+                }
+            }
+            MessageServer::RequestDiff { from, to } => {
+                self.on_request_diff(source_id, from, to).await
+            }
+            MessageServer::RequestBlame => self.on_request_blame(source_id).await,
+            MessageServer::RequestRevision { rev_num } => {
+                self.on_request_revision(source_id, rev_num).await
+            }
             _ => warn!("Received unexpected message type {:?}", message),
         }
     }
@@ -171,18 +1032,24 @@ impl Server {
 
 enum ServerMessage {
     Message(usize, MessageServer),
-    Connect(Client),
+    Connect(Client, JoinRequest),
     Disctonnect(usize),
+    Replay(usize, MessageServer),
 }
 
 #[derive(Clone)]
 pub struct ServerHandle {
     sender: mpsc::Sender<ServerMessage>,
+    next_id: Arc<AtomicUsize>,
 }
 
 impl ServerHandle {
-    pub async fn on_connect(&self, client: Client) {
-        self.send(ServerMessage::Connect(client)).await;
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub async fn on_connect(&self, client: Client, join: JoinRequest) {
+        self.send(ServerMessage::Connect(client, join)).await;
     }
 
     pub async fn on_disconnect(&self, client_id: usize) {
@@ -193,6 +1060,15 @@ impl ServerHandle {
         self.send(ServerMessage::Message(source_id, message)).await;
     }
 
+    pub async fn on_replay(&self, author: usize, message: MessageServer) {
+        self.send(ServerMessage::Replay(author, message)).await;
+    }
+
+    /// Keeps the ids below `count` for the participants of a replayed session.
+    pub fn reserve_ids(&self, count: usize) {
+        self.next_id.fetch_max(count, Ordering::Relaxed);
+    }
+
     async fn send(&self, message: ServerMessage) {
         if self.sender.send(message).await.is_err() {
             error!("Server receiver has been drop");
@@ -200,3 +1076,850 @@ impl ServerHandle {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::protocol::msg::Cursor;
+
+    use super::*;
+
+    async fn join(server: &mut Server, id: usize) -> mpsc::Receiver<MessageServer> {
+        let (tx, rx) = mpsc::channel(64);
+        server
+            .on_connect(Client::new(id, id.to_string(), tx), JoinRequest::default())
+            .await;
+        rx
+    }
+
+    fn threads(receiver: &mut mpsc::Receiver<MessageServer>) -> Vec<Thread> {
+        while let Ok(message) = receiver.try_recv() {
+            if let MessageServer::Threads { threads } = message {
+                return threads;
+            }
+        }
+        panic!("server should send the threads");
+    }
+
+    #[tokio::test]
+    async fn threads_follow_edits() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        let _bob = join(&mut server, 1).await;
+
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello world".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        let new_thread = NewThread {
+            start: 6,
+            end: 11,
+            comment: Comment {
+                text: "Who?".into(),
+                ..Default::default()
+            },
+            rev_num: Some(0),
+        };
+        server
+            .on_message(0, MessageServer::CreateThread(new_thread.clone()))
+            .await;
+
+        let mut delta = OperationSeq::default();
+        delta.insert("Big ");
+        delta.retain(11);
+        server
+            .on_message(
+                1,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+
+        // created before and after the concurrent edit
+        server
+            .on_message(0, MessageServer::CreateThread(new_thread))
+            .await;
+        server.on_message(0, MessageServer::ListThreads).await;
+
+        let threads = threads(&mut alice);
+        assert_eq!(threads.len(), 2);
+        for thread in threads {
+            assert_eq!((thread.start, thread.end), (10, 15));
+            assert_eq!(thread.rev_num, Some(1));
+            assert_eq!(thread.comments[0].author, Some(0));
+            assert_eq!(thread.comments[0].name.as_deref(), Some("Guest 0"));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_thread_range() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        let mut bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        drain(&mut alice);
+        drain(&mut bob);
+
+        for (start, end) in [(3, 1), (2, 6)] {
+            let new_thread = NewThread {
+                start,
+                end,
+                comment: Comment::default(),
+                rev_num: Some(0),
+            };
+            server
+                .on_message(0, MessageServer::CreateThread(new_thread))
+                .await;
+            assert_eq!(
+                alice.try_recv(),
+                Ok(MessageServer::Error {
+                    error: "Invalid thread range".into()
+                })
+            );
+        }
+        assert!(bob.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn thread_over_own_edits() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        let mut bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        let mut other = OperationSeq::default();
+        other.insert("Oh ");
+        other.retain(5);
+        server
+            .on_message(
+                0,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: other,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+
+        // bob comments on the text he just typed, before his edit is acknowledged
+
+        let mut own = OperationSeq::default();
+        own.retain(5);
+        own.insert(" world");
+        server
+            .on_message(
+                1,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: own,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+        drain(&mut alice);
+        drain(&mut bob);
+        let new_thread = NewThread {
+            start: 6,
+            end: 11,
+            comment: Comment::default(),
+            rev_num: Some(0),
+        };
+        server
+            .on_message(1, MessageServer::CreateThread(new_thread))
+            .await;
+        let Ok(MessageServer::ThreadUpdated(thread)) = bob.try_recv() else {
+            panic!("server should create the thread");
+        };
+        assert_eq!((thread.start, thread.end), (9, 14));
+
+        // his next edit moves the thread once
+
+        let mut next = OperationSeq::default();
+        next.insert("> ");
+        next.retain(14);
+        server
+            .on_message(
+                1,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: next,
+                    rev_num: 2,
+                    sealed: None,
+                }),
+            )
+            .await;
+        assert_eq!((server.threads[0].start, server.threads[0].end), (11, 16));
+    }
+
+    fn cursor(position: u64) -> MessageServer {
+        MessageServer::Cursor(CursorsInfo {
+            id: None,
+            cursors: vec![Cursor {
+                cursor: position,
+                anchor: position,
+            }],
+            rev_num: Some(0),
+        })
+    }
+
+    fn drain(receiver: &mut mpsc::Receiver<MessageServer>) -> Vec<MessageServer> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    fn roster(messages: &[MessageServer]) -> Vec<Participant> {
+        messages
+            .iter()
+            .rev()
+            .find_map(|message| match message {
+                MessageServer::Roster { participants } => Some(participants.clone()),
+                _ => None,
+            })
+            .expect("server should send the roster")
+    }
+
+    #[tokio::test]
+    async fn roster_follows_participants() {
+        let (mut server, _handle) = Server::new();
+        let (tx, mut alice) = mpsc::channel(64);
+        let join_request = JoinRequest {
+            name: Some("Alice".into()),
+            color: Some("#123456".into()),
+            ..Default::default()
+        };
+        server
+            .on_connect(Client::new(0, "0".into(), tx), join_request)
+            .await;
+        let _bob = join(&mut server, 1).await;
+        let _carol = join(&mut server, 2).await;
+
+        let guest = |id: usize, color: &str| Participant {
+            id,
+            name: format!("Guest {id}"),
+            color: color.into(),
+        };
+        let alice_participant = Participant {
+            id: 0,
+            name: "Alice".into(),
+            color: "#123456".into(),
+        };
+        let messages = drain(&mut alice);
+        assert!(messages.contains(&MessageServer::Joined(guest(1, "#9ACD32"))));
+        assert_eq!(
+            roster(&messages),
+            vec![
+                alice_participant.clone(),
+                guest(1, "#9ACD32"),
+                guest(2, "#4682B4")
+            ]
+        );
+
+        server.on_disconnect(1).await;
+        let messages = drain(&mut alice);
+        assert!(messages.contains(&MessageServer::Left { id: 1 }));
+        assert_eq!(
+            roster(&messages),
+            vec![alice_participant, guest(2, "#4682B4")]
+        );
+    }
+
+    #[tokio::test]
+    async fn cursors_for_late_joiners() {
+        let (mut server, _handle) = Server::new();
+        let _alice = join(&mut server, 0).await;
+        let _bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello world".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        server.on_message(0, cursor(6)).await;
+        let mut delta = OperationSeq::default();
+        delta.insert("Big ");
+        delta.retain(11);
+        server
+            .on_message(
+                1,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+
+        let mut carol = join(&mut server, 2).await;
+        assert!(
+            drain(&mut carol).contains(&MessageServer::Cursor(CursorsInfo {
+                id: Some(0),
+                cursors: vec![Cursor {
+                    cursor: 10,
+                    anchor: 10,
+                }],
+                rev_num: Some(1),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn presenter_mode() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        let mut bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello world".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+
+        server
+            .on_message(1, MessageServer::Present { enabled: true })
+            .await;
+        assert!(matches!(
+            drain(&mut bob).last(),
+            Some(MessageServer::Error { .. })
+        ));
+
+        server
+            .on_message(0, MessageServer::Present { enabled: true })
+            .await;
+        assert!(drain(&mut bob).contains(&MessageServer::PresenterChanged { id: Some(0) }));
+        drain(&mut alice);
+
+        server.on_message(1, cursor(2)).await;
+        server.on_message(0, cursor(4)).await;
+        assert!(drain(&mut alice).is_empty());
+        assert_eq!(drain(&mut bob).len(), 1);
+
+        // the held back cursors are sent once the presentation ends
+        server
+            .on_message(0, MessageServer::Present { enabled: false })
+            .await;
+        assert!(drain(&mut alice)
+            .iter()
+            .any(|message| matches!(message, MessageServer::Cursor(info) if info.id == Some(1))));
+    }
+
+    #[tokio::test]
+    async fn past_revisions() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        for rev_num in 0..SNAPSHOT_INTERVAL + 10 {
+            let mut delta = OperationSeq::default();
+            delta.retain(rev_num as u64);
+            delta.insert("a");
+            server
+                .on_message(
+                    0,
+                    MessageServer::ServerUpdate(ModifRequest {
+                        delta,
+                        rev_num,
+                        sealed: None,
+                    }),
+                )
+                .await;
+            drain(&mut alice);
+        }
+
+        for rev_num in [0, 3, SNAPSHOT_INTERVAL, SNAPSHOT_INTERVAL + 10] {
+            server
+                .on_message(0, MessageServer::RequestRevision { rev_num })
+                .await;
+            assert_eq!(
+                alice.try_recv(),
+                Ok(MessageServer::Revision {
+                    rev_num,
+                    file: "a".repeat(rev_num),
+                })
+            );
+        }
+
+        server
+            .on_message(
+                0,
+                MessageServer::RequestRevision {
+                    rev_num: SNAPSHOT_INTERVAL + 11,
+                },
+            )
+            .await;
+        assert!(matches!(alice.try_recv(), Ok(MessageServer::Error { .. })));
+    }
+
+    #[tokio::test]
+    async fn diff_between_revisions() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "one\ntwo\n".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        for (rev_num, text) in ["three\n", "four\n"].into_iter().enumerate() {
+            let mut delta = OperationSeq::default();
+            delta.retain(8 + 6 * rev_num as u64);
+            delta.insert(text);
+            server
+                .on_message(
+                    0,
+                    MessageServer::ServerUpdate(ModifRequest {
+                        delta,
+                        rev_num,
+                        sealed: None,
+                    }),
+                )
+                .await;
+        }
+        drain(&mut alice);
+
+        server
+            .on_message(0, MessageServer::RequestDiff { from: 0, to: 2 })
+            .await;
+
+        let mut delta = OperationSeq::default();
+        delta.retain(8);
+        delta.insert("three\nfour\n");
+        assert_eq!(
+            alice.try_recv(),
+            Ok(MessageServer::Diff(RevisionDiff {
+                from: 0,
+                to: 2,
+                delta,
+                unified:
+                    "--- revision 0\n+++ revision 2\n@@ -1,2 +1,4 @@\n one\n two\n+three\n+four\n"
+                        .into(),
+            }))
+        );
+
+        server
+            .on_message(0, MessageServer::RequestDiff { from: 2, to: 1 })
+            .await;
+        assert!(matches!(alice.try_recv(), Ok(MessageServer::Error { .. })));
+    }
+
+    #[tokio::test]
+    async fn revert_to_checkpoint() {
+        let (mut server, _handle) = Server::new();
+        let mut alice = join(&mut server, 0).await;
+        let mut bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        server
+            .on_message(
+                0,
+                MessageServer::Checkpoint {
+                    name: "start".into(),
+                },
+            )
+            .await;
+        let mut delta = OperationSeq::default();
+        delta.retain(5);
+        delta.insert(" world");
+        server
+            .on_message(
+                1,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+        drain(&mut alice);
+        drain(&mut bob);
+
+        server
+            .on_message(
+                0,
+                MessageServer::RevertToCheckpoint {
+                    name: "start".into(),
+                },
+            )
+            .await;
+
+        // even the author of the revert receives it as a remote change
+        let mut delta = OperationSeq::default();
+        delta.retain(5);
+        delta.delete(6);
+        let update = MessageServer::ServerUpdate(ModifRequest {
+            delta,
+            rev_num: 2,
+            sealed: None,
+        });
+        assert_eq!(drain(&mut alice), vec![update.clone()]);
+        assert_eq!(drain(&mut bob), vec![update]);
+        assert_eq!(server.file.as_ref().unwrap().to_string(), "Hello");
+
+        server
+            .on_message(0, MessageServer::RevertToCheckpoint { name: "end".into() })
+            .await;
+        assert_eq!(
+            alice.try_recv(),
+            Ok(MessageServer::Error {
+                error: "Unknown checkpoint end".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_trail() {
+        let path = std::env::temp_dir().join(format!("smartshare-{}.audit", std::process::id()));
+        let audit_log = tokio::fs::File::create(&path).await.unwrap();
+        let (server, _handle) = Server::new();
+        let mut server = server.with_audit_log(audit_log);
+        let mut alice = join(&mut server, 0).await;
+        let _bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        for (rev_num, author) in [(0, 1), (1, 0), (2, 1)] {
+            let mut delta = OperationSeq::default();
+            delta.retain(5 + rev_num);
+            delta.insert("!");
+            server
+                .on_message(
+                    author,
+                    MessageServer::ServerUpdate(ModifRequest {
+                        delta,
+                        rev_num: rev_num as usize,
+                        sealed: None,
+                    }),
+                )
+                .await;
+        }
+        drain(&mut alice);
+
+        server
+            .on_message(
+                0,
+                MessageServer::RequestAudit(AuditQuery {
+                    from: Some(1),
+                    to: Some(2),
+                    author: Some(1),
+                }),
+            )
+            .await;
+        let Ok(MessageServer::Audit { entries }) = alice.try_recv() else {
+            panic!("server should answer with the audit entries");
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].rev_num, 1);
+        assert_eq!(entries[0].identity, "1");
+        assert_eq!(entries[0].name.as_deref(), Some("Guest 1"));
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let logged: Vec<AuditEntry> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            logged.iter().map(|entry| entry.author).collect::<Vec<_>>(),
+            vec![0, 1, 0, 1]
+        );
+        assert_eq!(logged[1], entries[0]);
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("smartshare-{}.rec", std::process::id()));
+        let recording = tokio::fs::File::create(&path).await.unwrap();
+        let (server, _handle) = Server::new();
+        let mut server = server.with_recording(recording);
+        let mut alice = join(&mut server, 0).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        let mut delta = OperationSeq::default();
+        delta.retain(5);
+        delta.insert("!");
+        server
+            .on_message(
+                0,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: delta.clone(),
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+        server.on_message(0, cursor(6)).await;
+        drain(&mut alice);
+
+        let events = crate::server::recording::load(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.author == 0));
+        assert!(matches!(events[0].message, MessageServer::Joined(_)));
+
+        let (start, started) = oneshot::channel();
+        let (server, handle) = Server::new();
+        let mut server = server.with_playback(start);
+        handle.reserve_ids(1);
+        tokio::spawn(crate::server::recording::play(
+            events,
+            handle.clone(),
+            1.0,
+            started,
+        ));
+        tokio::spawn(async move {
+            server.run().await;
+        });
+        let (tx, mut viewer) = mpsc::channel(64);
+        let viewer_id = handle.next_id();
+        handle
+            .on_connect(
+                Client::new(viewer_id, viewer_id.to_string(), tx),
+                JoinRequest::default(),
+            )
+            .await;
+
+        let mut messages = vec![];
+        while !messages
+            .iter()
+            .any(|message| matches!(message, MessageServer::Cursor(_)))
+        {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(1), viewer.recv())
+                .await
+                .expect("playback should reach the cursor")
+                .unwrap();
+            messages.push(message);
+        }
+        assert!(!messages.contains(&MessageServer::RequestFile));
+        assert!(messages.contains(&MessageServer::File {
+            file: "Hello".into(),
+            version: 0,
+            sealed: None,
+        }));
+        assert!(
+            messages.contains(&MessageServer::ServerUpdate(ModifRequest {
+                delta: delta.clone(),
+                rev_num: 1,
+                sealed: None,
+            }))
+        );
+        assert!(messages
+            .iter()
+            .any(|message| matches!(message, MessageServer::Cursor(info) if info.id == Some(0))));
+
+        handle
+            .on_message(
+                viewer_id,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta,
+                    rev_num: 1,
+                    sealed: None,
+                }),
+            )
+            .await;
+        assert!(matches!(
+            viewer.recv().await,
+            Some(MessageServer::Error { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn resume_after_disconnection() {
+        let (mut server, _handle) = Server::new();
+        let _alice = join(&mut server, 0).await;
+        let _bob = join(&mut server, 1).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        let mut own = OperationSeq::default();
+        own.retain(5);
+        own.insert("!");
+        server
+            .on_message(
+                1,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: own,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+        server.on_disconnect(1).await;
+        let mut other = OperationSeq::default();
+        other.insert("Oh ");
+        other.retain(6);
+        server
+            .on_message(
+                0,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: other.clone(),
+                    rev_num: 1,
+                    sealed: None,
+                }),
+            )
+            .await;
+
+        let (tx, mut bob) = mpsc::channel(64);
+        let join = JoinRequest {
+            resume: Some(Resume {
+                rev_num: 0,
+                id: 1,
+                session: server.session.clone(),
+            }),
+            ..Default::default()
+        };
+        server
+            .on_connect(Client::new(2, "2".into(), tx), join)
+            .await;
+
+        let messages = drain(&mut bob);
+        assert_eq!(
+            messages[..4],
+            [
+                MessageServer::Welcome {
+                    id: 2,
+                    session: server.session.clone(),
+                },
+                MessageServer::Ack,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta: other,
+                    rev_num: 2,
+                    sealed: None,
+                }),
+                MessageServer::Resumed,
+            ]
+        );
+        assert!(!messages
+            .iter()
+            .any(|message| matches!(message, MessageServer::File { .. })));
+    }
+
+    #[tokio::test]
+    async fn resume_across_sessions() {
+        let (mut server, _handle) = Server::new();
+        let _alice = join(&mut server, 0).await;
+        server
+            .on_message(
+                0,
+                MessageServer::File {
+                    file: "Hello".into(),
+                    version: 0,
+                    sealed: None,
+                },
+            )
+            .await;
+        let mut delta = OperationSeq::default();
+        delta.retain(5);
+        delta.insert("!");
+        server
+            .on_message(
+                0,
+                MessageServer::ServerUpdate(ModifRequest {
+                    delta,
+                    rev_num: 0,
+                    sealed: None,
+                }),
+            )
+            .await;
+
+        // the same id and revision, but from before a restart
+
+        let (tx, mut bob) = mpsc::channel(64);
+        let join = JoinRequest {
+            resume: Some(Resume {
+                rev_num: 0,
+                id: 0,
+                session: crypto::random(16),
+            }),
+            ..Default::default()
+        };
+        server
+            .on_connect(Client::new(1, "1".into(), tx), join)
+            .await;
+
+        let messages = drain(&mut bob);
+        assert_eq!(
+            messages[1],
+            MessageServer::File {
+                file: "Hello!".into(),
+                version: 1,
+                sealed: None,
+            }
+        );
+        assert!(!messages.iter().any(|message| matches!(
+            message,
+            MessageServer::Ack | MessageServer::Resumed | MessageServer::Error { .. }
+        )));
+    }
+}